@@ -0,0 +1,59 @@
+use prometheus_client::{encoding::text::encode, registry::Registry};
+use std::collections::BTreeMap;
+
+//bytes sent and received, counted by the transport layer through libp2p's bandwidth metrics.
+//libp2p only records them per transport protocol stack (e.g. /ip4/tcp), not per peer.
+#[derive(Default)]
+pub struct Bandwidth {
+    registry: Registry,
+}
+
+impl Bandwidth {
+    //the registry handed to the swarm builder's with_bandwidth_metrics.
+    pub fn registry_mut(&mut self) -> &mut Registry {
+        &mut self.registry
+    }
+
+    pub fn report(&self) -> String {
+        let mut encoded = String::new();
+        if encode(&mut encoded, &self.registry).is_err() {
+            return "Bandwidth: unavailable".to_string();
+        }
+
+        //(inbound, outbound) bytes per protocol stack, read from lines like:
+        //libp2p_bandwidth_bytes_total{protocols="/ip4/tcp",direction="Inbound"} 1234
+        let mut stacks: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for line in encoded
+            .lines()
+            .filter(|line| line.starts_with("libp2p_bandwidth"))
+        {
+            let Some((labels, value)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            let Ok(bytes) = value.parse::<u64>() else {
+                continue;
+            };
+            let protocols = labels
+                .split("protocols=\"")
+                .nth(1)
+                .and_then(|rest| rest.split('"').next())
+                .unwrap_or("unknown");
+            let entry = stacks.entry(protocols.to_string()).or_default();
+            if labels.contains("direction=\"Inbound\"") {
+                entry.0 += bytes;
+            } else {
+                entry.1 += bytes;
+            }
+        }
+
+        let inbound: u64 = stacks.values().map(|(inbound, _)| inbound).sum();
+        let outbound: u64 = stacks.values().map(|(_, outbound)| outbound).sum();
+        let mut report = format!("Bandwidth: inbound {inbound} bytes, outbound {outbound} bytes");
+        for (protocols, (inbound, outbound)) in stacks {
+            report.push_str(&format!(
+                "\n  {protocols}: inbound {inbound} bytes, outbound {outbound} bytes"
+            ));
+        }
+        report
+    }
+}
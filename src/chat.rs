@@ -1,90 +1,442 @@
-use std::{error::Error, time::Duration};
+use std::{collections::HashSet, error::Error, time::Duration};
 
+use clap::{Parser, ValueEnum};
 use futures::stream::StreamExt;
 use libp2p::{
-    gossipsub, mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    allow_block_list,
+    core::transport::OptionalTransport,
+    gossipsub, mdns,
+    swarm::{dial_opts::DialOpts, NetworkBehaviour, SwarmEvent},
+    PeerId,
 };
-use tokio::{io, io::AsyncBufReadExt, select};
+use tokio::select;
+use tracing::info;
+mod bandwidth;
+mod compression;
+mod config;
+mod display;
+mod exit;
+mod health;
+mod outbound_queue;
+mod sequence;
+mod transport;
+mod utils;
 
-//a custom network behaviour that combines Gossipsub and Mdns.
+#[derive(Parser, Debug)]
+#[command(name = "chat", args_override_self = true)]
+struct Opt {
+    #[command(flatten)]
+    common: config::CommonOpts,
+
+    #[command(flatten)]
+    gossipsub: config::GossipsubOpts,
+
+    /// Disable colored output (also disabled automatically when stdout is not a terminal).
+    #[arg(long)]
+    no_color: bool,
+
+    /// Number of recently printed message ids remembered to avoid printing a message twice (0 disables).
+    #[arg(long, default_value_t = 256)]
+    seen_cache_size: usize,
+
+    /// When mDNS expires a peer, keep it if it is still connected or can be dialed again,
+    /// instead of dropping it right away. Delays the removal of peers that are really gone.
+    #[arg(long)]
+    mdns_verify_expiry: bool,
+
+    /// Seconds to wait before removing an explicit peer whose mDNS record expired (0 removes it right away).
+    /// The removal is called off if the peer is discovered or connects again meanwhile, so brief blips don't churn the mesh.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    explicit_peer_grace: u64,
+
+    /// Transports to build the swarm with.
+    #[arg(long, value_enum, default_value_t = TransportMode::QuicAndTcp)]
+    transport: TransportMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum TransportMode {
+    QuicAndTcp,
+    //for networks where only UDP gets through. QUIC multiplexes natively, so no yamux is involved.
+    QuicOnly,
+}
+
+//a custom network behaviour that combines Gossipsub and Mdns, plus the peers banned from connecting.
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
     mdns: mdns::tokio::Behaviour,
+    blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
 }
 
+//the stdin commands, listed by the help command.
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "list these commands"),
+    ("bandwidth", "print the bytes sent and received so far"),
+    ("who", "list the peers subscribed to our topic"),
+    (
+        "peers",
+        "list our topic's mesh peers apart from the peers only subscribed to it",
+    ),
+    (
+        "resync",
+        "re-add the configured explicit peers, our subscriptions can't be re-sent without leaving the topic",
+    ),
+    ("dial <multiaddr>", "dial a peer"),
+    ("disconnect <peer id>", "close the connections to a peer"),
+    (
+        "ban <peer id>",
+        "disconnect a peer and keep it from reconnecting",
+    ),
+    ("unban <peer id>", "allow a banned peer to connect again"),
+];
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+async fn main() {
+    exit::finish(run().await)
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let opt: Opt = config::parse_args().map_err(exit::config)?;
+    utils::set_peer_id_format(opt.common.peer_id_format);
+    utils::init_tracing();
+
+    let local_key = utils::local_keypair(
+        opt.common.secret_key_seed,
+        opt.common.identity_file.as_deref(),
+        opt.common.rotate_identity,
+    )
+    .map_err(exit::config)?;
+    if opt.common.print_peer_id {
+        let listen_addresses: &[&str] = match opt.transport {
+            TransportMode::QuicAndTcp => &["/ip4/0.0.0.0/udp/0/quic-v1", "/ip4/0.0.0.0/tcp/0"],
+            TransportMode::QuicOnly => &["/ip4/0.0.0.0/udp/0/quic-v1", "/ip6/::/udp/0/quic-v1"],
+        };
+        utils::print_peer_id(&local_key, listen_addresses);
+        return Ok(());
+    }
+    println!(
+        "Local peer id: {}",
+        utils::fmt_peer_id(&local_key.public().to_peer_id())
+    );
+
+    let gossipsub_config = opt
+        .gossipsub
+        .config(|builder| {
+            builder.validation_mode(gossipsub::ValidationMode::Strict); //validate message signing.
+        })
+        .map_err(exit::config)?;
+    let mut bandwidth = bandwidth::Bandwidth::default();
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new, //cryptographic handshake, ensures secure communication between peers.
-            yamux::Config::default, //parallel streams.
-        )?
         .with_quic()
+        .with_other_transport(|key| {
+            let tcp = match opt.transport {
+                TransportMode::QuicAndTcp => OptionalTransport::some(transport::tcp_transport(
+                    key,
+                    opt.common.security,
+                    opt.common.yamux_config(),
+                    None,
+                )?),
+                TransportMode::QuicOnly => OptionalTransport::none(),
+            };
+            Ok::<_, Box<dyn Error + Send + Sync>>(tcp)
+        })?
+        .with_bandwidth_metrics(bandwidth.registry_mut())
         .with_behaviour(|key| {
             let gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()), //sign published messages.
-                gossipsub::ConfigBuilder::default()
-                    .validation_mode(gossipsub::ValidationMode::Strict) //validate message signing.
-                    .build()?,
+                gossipsub_config,
             )?;
 
             let mdns =
                 mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MyBehaviour { gossipsub, mdns })
+            Ok(MyBehaviour {
+                gossipsub,
+                mdns,
+                blocked: allow_block_list::Behaviour::default(),
+            })
         })?
         .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX))) //keep connections open when idle
         .build();
 
+    println!("Gossipsub {}", opt.gossipsub.summary());
+
     let topic = gossipsub::IdentTopic::new("paly-p2p-chat");
     swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    opt.gossipsub.log_topic_params(&topic.hash());
 
-    //listen on all interfaces and whatever port the OS assigns.
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    let transports = utils::Transports {
+        tcp: opt.transport == TransportMode::QuicAndTcp,
+        quic: true,
+        dns: false,
+    };
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines(); //read full lines from stdin
+    //explicit peers always receive our messages; direct peers are explicit peers we also dial.
+    for peer_id in &opt.gossipsub.explicit_peers {
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(peer_id);
+    }
+    for address in &opt.gossipsub.direct_peers {
+        let peer_id = utils::peer_id_of(address).ok_or_else(|| {
+            exit::config(format!(
+                "direct peer {address} is missing its /p2p/<peer id>"
+            ))
+        })?;
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        utils::dial(&mut swarm, address.clone(), transports).map_err(exit::bootstrap)?;
+    }
+    if !opt.gossipsub.explicit_peers.is_empty() || !opt.gossipsub.direct_peers.is_empty() {
+        let explicit_peers: Vec<_> = opt
+            .gossipsub
+            .explicit_peers
+            .iter()
+            .map(utils::fmt_peer_id)
+            .collect();
+        println!(
+            "Explicit peers: [{}], direct peers: {:?}",
+            explicit_peers.join(", "),
+            opt.gossipsub.direct_peers
+        );
+    }
+    let mut dial_queue = utils::DialQueue::new(
+        utils::startup_peers(&[], opt.common.peers_file.as_deref()).map_err(exit::config)?,
+        opt.common.dial_concurrency as usize,
+        transports,
+    );
+    dial_queue.fill(&mut swarm);
+
+    //listen on all interfaces and --listen-port, by default whatever port the OS assigns.
+    let port = opt.common.listen_port;
+    let on_conflict = opt.common.on_port_conflict;
+    let mut listeners = vec![transport::listen_on(
+        &mut swarm,
+        format!("/ip4/0.0.0.0/udp/{port}/quic-v1").parse()?,
+        on_conflict,
+    )
+    .map_err(exit::network)?];
+    match opt.transport {
+        TransportMode::QuicAndTcp => {
+            println!("Transport: QUIC and TCP");
+            listeners.push(
+                transport::listen_on(
+                    &mut swarm,
+                    format!("/ip4/0.0.0.0/tcp/{port}").parse()?,
+                    on_conflict,
+                )
+                .map_err(exit::network)?,
+            );
+        }
+        TransportMode::QuicOnly => {
+            println!("Transport: QUIC only");
+            //not every host has IPv6, so failing to listen on it is not fatal.
+            match transport::listen_on(
+                &mut swarm,
+                format!("/ip6/::/udp/{port}/quic-v1").parse()?,
+                on_conflict,
+            ) {
+                Ok(listener_id) => listeners.push(listener_id),
+                Err(e) => eprintln!("Not listening on IPv6: {e}"),
+            }
+        }
+    }
+    utils::announce_addresses(&mut swarm, &opt.common.announce_addresses);
+
+    let health = health::Health::new(opt.common.health_min_peers);
+    if let Some(addr) = opt.common.health_addr {
+        health::spawn(addr, health.clone()).await?;
+    }
+    let mut bandwidth_report = utils::interval_secs(opt.common.bandwidth_report_interval);
+
+    let style = display::Style::new(opt.no_color);
+    let mut seen_messages = display::SeenMessages::new(opt.seen_cache_size);
+    let display_queue =
+        display::DisplayQueue::spawn(opt.gossipsub.display_queue as usize, opt.gossipsub.overflow);
+    let mut verifying_expiry: HashSet<PeerId> = HashSet::new(); //expired mDNS peers being dialed again
+    let mut pending_removals =
+        utils::PendingRemovals::new(Duration::from_secs(opt.explicit_peer_grace));
+    let explicit_peers = opt.gossipsub.configured_explicit_peers();
+    let mut subscribers = utils::Subscribers::default();
+    let mut sequencer = sequence::Sequencer::new(opt.gossipsub.track_sequence);
+    let mut sequence_tracker = sequence::Tracker::new(opt.gossipsub.track_sequence);
+    let mut mesh_wait = utils::MeshWait::new(opt.gossipsub.wait_for_mesh.map(Duration::from_secs));
+    let mut outbound_queue = outbound_queue::OutboundQueue::open(
+        opt.gossipsub.outbound_queue.clone(),
+        opt.gossipsub.outbound_queue_size as usize,
+    )
+    .map_err(exit::config)?;
+    let mut shutdown = utils::ShutdownSignal::listen();
+    let mut ready_file = utils::ReadyFile::new(opt.common.ready_file.clone());
+    let mut listen_log = utils::ListenLog::new(Duration::from_millis(opt.common.listen_settle));
+    let mut stdin = utils::StdinLines::new(opt.common.max_line_length as usize); //read full lines from stdin
 
     loop {
         select! {
-            Ok(Some(line)) = stdin.next_line() => { //publish the message.
-                if let Err(e) = swarm
-                    .behaviour_mut().gossipsub
-                    .publish(topic.clone(), line.as_bytes()) {
-                    println!("Publish error: {e:?}");
+            Ok(Some(line)) = stdin.next_line() => match utils::parse_input(&line, &opt.common.command_prefix) {
+                utils::Input::Command("help") => utils::print_help(&opt.common.command_prefix, COMMANDS),
+                utils::Input::Command("bandwidth") => println!("{}", bandwidth.report()),
+                utils::Input::Command("peers") => utils::print_topic_peers(&swarm.behaviour().gossipsub, &topic.hash()),
+                utils::Input::Command("who") => {
+                    let peers = subscribers.of(&topic.hash());
+                    println!("{} peer(s) subscribed to {topic}", peers.len());
+                    for peer_id in peers {
+                        println!("  {peer_id}", peer_id = utils::fmt_peer_id(&peer_id));
+                    }
+                }
+                utils::Input::Command("resync") => {
+                    utils::resync_gossipsub(&mut swarm.behaviour_mut().gossipsub, &explicit_peers)
+                }
+                utils::Input::Command(command) => {
+                    if let Some(address) = command.strip_prefix("dial ") {
+                        //the outcome shows up as an established connection or a connection error.
+                        match utils::parse_legacy_multiaddr(address.trim()) {
+                            Ok(address) => match utils::dial(&mut swarm, address.clone(), transports) {
+                                Ok(true) => println!("Dialing {address}"),
+                                Ok(false) => {}
+                                Err(e) => println!("Failed to dial {address}: {e}"),
+                            },
+                            Err(e) => eprintln!("Invalid address {address}: {e}"),
+                        }
+                    } else if !utils::handle_peer_command(&mut swarm, command, |behaviour| {
+                        (&mut behaviour.gossipsub, &mut behaviour.blocked)
+                    }) {
+                        utils::unknown_command(&opt.common.command_prefix, command);
+                    }
+                }
+                utils::Input::Message(_) if opt.gossipsub.read_only => println!("Read-only mode, not publishing"),
+                utils::Input::Message(message) if mesh_wait.waiting() => mesh_wait.hold(message),
+                utils::Input::Message(message) => outbound_queue.publish(&mut swarm.behaviour_mut().gossipsub, &topic, message, &opt.gossipsub, &mut sequencer),
+            },
+            _ = mesh_wait.check() => {
+                for line in mesh_wait.ready(&swarm.behaviour().gossipsub, &topic.hash()) {
+                    outbound_queue.publish(&mut swarm.behaviour_mut().gossipsub, &topic, &line, &opt.gossipsub, &mut sequencer);
                 }
             }
+            _ = outbound_queue.check() => outbound_queue.flush(&mut swarm.behaviour_mut().gossipsub, &topic),
+            _ = utils::tick(&mut bandwidth_report) => println!("{}", bandwidth.report()),
+            _ = listen_log.check() => listen_log.summarize(),
+            signal = shutdown.recv() => {
+                let grace = Duration::from_secs(opt.common.shutdown_grace);
+                utils::begin_shutdown(&mut swarm, signal, &listeners, grace, &ready_file);
+                break;
+            }
+            peer_id = pending_removals.expired() => {
+                println!("{peer_id} did not come back within the grace period, removing it", peer_id = utils::fmt_peer_id(&peer_id));
+                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+            }
             event = swarm.select_next_some() => match event { //handle network behaviour's events.
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                     for (peer_id, _multiaddr) in list {
-                        println!("mDNS discovered a new peer: {peer_id}");
+                        info!(target: "play_p2p::mdns", "discovered a new peer: {peer_id}", peer_id = utils::fmt_peer_id(&peer_id));
+                        if swarm.behaviour().blocked.blocked_peers().contains(&peer_id) {
+                            continue;
+                        }
+                        if pending_removals.cancel(&peer_id) {
+                            println!("{peer_id} is back, keeping it", peer_id = utils::fmt_peer_id(&peer_id));
+                        }
                         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        println!("mDNS discover peer has expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                    for (peer_id, multiaddr) in list {
+                        info!(target: "play_p2p::mdns", "discovered peer has expired: {peer_id}", peer_id = utils::fmt_peer_id(&peer_id));
+                        if !opt.mdns_verify_expiry {
+                            if opt.explicit_peer_grace == 0 {
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                            } else {
+                                println!("Removing {peer_id} in {}s unless it comes back", opt.explicit_peer_grace, peer_id = utils::fmt_peer_id(&peer_id));
+                                pending_removals.schedule(peer_id);
+                            }
+                        } else if swarm.is_connected(&peer_id) {
+                            println!("{peer_id} is still connected, keeping it", peer_id = utils::fmt_peer_id(&peer_id));
+                        } else if verifying_expiry.insert(peer_id) {
+                            //the peer is only removed if this dial fails.
+                            let opts = DialOpts::peer_id(peer_id).addresses(vec![multiaddr]).build();
+                            if let Err(e) = swarm.dial(opts) {
+                                println!("{peer_id} could not be dialed again, removing it: {e}", peer_id = utils::fmt_peer_id(&peer_id));
+                                verifying_expiry.remove(&peer_id);
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                            }
+                        }
                     }
                 },
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                     propagation_source: peer_id,
                     message_id: id,
                     message,
-                })) => println!(
-                        "Received message: '{}' with id: {id} from peer: {peer_id}",
-                        String::from_utf8_lossy(&message.data),
-                        //can persist the message locally (SQLite, file, etc.)
-                    ),
+                })) => {
+                    opt.gossipsub.report_validation(&mut swarm.behaviour_mut().gossipsub, &id, &peer_id);
+                    if seen_messages.insert(&id) {
+                        let (sequence, data) = sequence::split(&message.data);
+                        if let (Some(sequence), Some(source)) = (sequence, message.source) {
+                            sequence_tracker.received(source, sequence);
+                        }
+                        display_queue.push(format!(
+                            "{} Received message: '{}' with id: {id} from peer: {}, published by {}",
+                            style.timestamp(),
+                            display::payload(&compression::decompress(data)),
+                            style.peer(&peer_id),
+                            display::origin(&message),
+                            //can persist the message locally (SQLite, file, etc.)
+                        )).await;
+                    }
+                }
                 SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Local node is listening on {address}");
+                    let local_peer_id = *swarm.local_peer_id();
+                    let addresses = utils::dialable_addresses(&address, local_peer_id, opt.common.include_loopback);
+                    for address in &addresses {
+                        listen_log.listening(address.to_string());
+                    }
+                    ready_file.listening(local_peer_id, addresses);
                 }
-                connection_event => println!("{connection_event:?}"),
+                SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                    if dial_queue.finished(connection_id, Ok(peer_id)) {
+                        dial_queue.fill(&mut swarm);
+                    }
+                    let address = endpoint.get_remote_address();
+                    info!(
+                        target: "play_p2p::swarm",
+                        "Connection established with {peer_id} at {address} using {}",
+                        transport::security_of(address, opt.common.security), peer_id = utils::fmt_peer_id(&peer_id)
+                    );
+                    //a configured explicit peer may have been dropped as explicit while it was away.
+                    if explicit_peers.contains(&peer_id) {
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    }
+                    if verifying_expiry.remove(&peer_id) {
+                        println!("{peer_id} is still reachable, keeping it", peer_id = utils::fmt_peer_id(&peer_id));
+                    }
+                    if pending_removals.cancel(&peer_id) {
+                        println!("{peer_id} reconnected, keeping it", peer_id = utils::fmt_peer_id(&peer_id));
+                    }
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } if verifying_expiry.remove(&peer_id) => {
+                    println!("{peer_id} is no longer reachable, removing it: {error}", peer_id = utils::fmt_peer_id(&peer_id));
+                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                    let count = subscribers.subscribed(peer_id, &topic);
+                    info!(target: "play_p2p::gossipsub", "{peer_id} subscribed to {topic} ({count} subscriber(s))", peer_id = utils::fmt_peer_id(&peer_id));
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                    let count = subscribers.unsubscribed(&peer_id, &topic);
+                    info!(target: "play_p2p::gossipsub", "{peer_id} unsubscribed from {topic} ({count} subscriber(s))", peer_id = utils::fmt_peer_id(&peer_id));
+                }
+                event @ SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    subscribers.disconnected(&peer_id);
+                    info!(target: "play_p2p::swarm", "{event:?}");
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, connection_id, error, .. } => {
+                    if dial_queue.finished(connection_id, Err(&error)) {
+                        dial_queue.fill(&mut swarm);
+                    }
+                    transport::print_dial_error(peer_id, &error, false);
+                }
+                connection_event => info!(target: "play_p2p::swarm", "{connection_event:?}"),
             }
         }
+        health.update(&swarm);
     }
+
+    drop(ready_file);
+    utils::end_shutdown()
 }
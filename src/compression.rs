@@ -0,0 +1,128 @@
+use crate::config::Compression;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use std::{
+    borrow::Cow,
+    io::{self, Read, Write},
+};
+
+//compressed payloads start with this marker and a codec byte. anything else is a plain payload, so nodes with different
+//--compress settings, or without compression support at all, can share a topic.
+const MAGIC: &[u8] = b"\0pz";
+const GZIP: u8 = 1;
+const ZSTD: u8 = 2;
+
+//decompression stops here, so a tiny message can't expand into gigabytes.
+const MAX_DECOMPRESSED_BYTES: u64 = 16 * 1024 * 1024;
+
+pub fn compress(payload: Vec<u8>, compression: Compression) -> io::Result<Vec<u8>> {
+    let (codec, compressed) = match compression {
+        Compression::None => return Ok(payload),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&payload)?;
+            (GZIP, encoder.finish()?)
+        }
+        Compression::Zstd => (ZSTD, zstd::encode_all(payload.as_slice(), 0)?),
+    };
+
+    let mut framed = Vec::with_capacity(MAGIC.len() + 1 + compressed.len());
+    framed.extend_from_slice(MAGIC);
+    framed.push(codec);
+    framed.extend_from_slice(&compressed);
+    Ok(framed)
+}
+
+//received payloads are decompressed whatever our own --compress setting is.
+//data without the marker, or that fails to decompress, is returned as is.
+pub fn decompress(data: &[u8]) -> Cow<'_, [u8]> {
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        return Cow::Borrowed(data);
+    };
+    let decompressed = match rest.split_first() {
+        Some((&GZIP, compressed)) => read_limited(GzDecoder::new(compressed)),
+        Some((&ZSTD, compressed)) => {
+            zstd::stream::read::Decoder::new(compressed).and_then(read_limited)
+        }
+        _ => return Cow::Borrowed(data),
+    };
+    match decompressed {
+        Ok(payload) => Cow::Owned(payload),
+        Err(_) => Cow::Borrowed(data),
+    }
+}
+
+fn read_limited(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader
+        .take(MAX_DECOMPRESSED_BYTES + 1)
+        .read_to_end(&mut payload)?;
+    if payload.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(io::Error::other("decompressed payload too large"));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODECS: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    //large and not too repetitive, so compression has some work to do.
+    fn large_payload() -> Vec<u8> {
+        (0..1024 * 1024_u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        for compression in CODECS {
+            for payload in [Vec::new(), b"hello".to_vec(), large_payload()] {
+                let compressed = compress(payload.clone(), compression).unwrap();
+                assert_eq!(
+                    decompress(&compressed).as_ref(),
+                    payload.as_slice(),
+                    "{compression:?}, {} bytes",
+                    payload.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn none_leaves_the_payload_as_is() {
+        let payload = b"plain".to_vec();
+        assert_eq!(
+            compress(payload.clone(), Compression::None).unwrap(),
+            payload
+        );
+        assert!(matches!(decompress(&payload), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn corrupted_frames_are_returned_as_is() {
+        for codec in [GZIP, ZSTD, 0xff] {
+            let mut framed = MAGIC.to_vec();
+            framed.push(codec);
+            framed.extend_from_slice(b"not compressed");
+            assert_eq!(decompress(&framed).as_ref(), framed.as_slice());
+        }
+        //a valid frame cut short.
+        let compressed = compress(large_payload(), Compression::Gzip).unwrap();
+        let truncated = &compressed[..compressed.len() / 2];
+        assert_eq!(decompress(truncated).as_ref(), truncated);
+    }
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        let payload = vec![0; MAX_DECOMPRESSED_BYTES as usize + 1];
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compress(payload.clone(), compression).unwrap();
+            assert_eq!(decompress(&compressed).as_ref(), compressed.as_slice());
+        }
+        let payload = vec![0; MAX_DECOMPRESSED_BYTES as usize];
+        let compressed = compress(payload.clone(), Compression::Zstd).unwrap();
+        assert_eq!(decompress(&compressed).len(), payload.len());
+    }
+}
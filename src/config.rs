@@ -0,0 +1,608 @@
+//shared by all binaries, each of which only flattens the option groups it needs.
+#![allow(dead_code)]
+
+use clap::{value_parser, ArgAction, Args, Command, Parser, ValueEnum};
+use libp2p::{gossipsub, relay, yamux, Multiaddr, PeerId};
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::info;
+
+//options shared by all binaries, flattened into each binary's own command line parser.
+#[derive(Args, Debug)]
+pub struct CommonOpts {
+    /// TOML file of options keyed by their long name (e.g. `security = "tls"`), overridden by the command line.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Address to advertise to other peers instead of the bound one (repeatable),
+    /// e.g. the public address of a cloud host behind a NAT or load balancer.
+    #[arg(long = "announce-address", value_name = "MULTIADDR")]
+    pub announce_addresses: Vec<Multiaddr>,
+
+    /// Serve a JSON health report over HTTP on this address, e.g. 127.0.0.1:8080.
+    #[arg(long)]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Minimum number of connected peers for the health endpoint to report healthy.
+    #[arg(long, default_value_t = 0)]
+    pub health_min_peers: usize,
+
+    /// Fixed value to generate a deterministic peer id, handy for reproducible multi-node setups.
+    #[arg(long)]
+    pub secret_key_seed: Option<u8>,
+
+    /// File holding the node identity as a protobuf-encoded keypair, created with a new key if it doesn't exist.
+    #[arg(long, value_name = "FILE", conflicts_with = "secret_key_seed")]
+    pub identity_file: Option<PathBuf>,
+
+    /// Replace the key in --identity-file with a new one before starting, keeping the previous key as FILE.old.
+    #[arg(long, requires = "identity_file")]
+    pub rotate_identity: bool,
+
+    /// Print the peer id of the node identity and exit without starting the node.
+    #[arg(long)]
+    pub print_peer_id: bool,
+
+    /// How peer ids are printed: base58 (12D3KooW...) or base32 CIDv1 (bafzaa...), as some tooling expects.
+    #[arg(long, value_enum, default_value_t = PeerIdFormat::Base58)]
+    pub peer_id_format: PeerIdFormat,
+
+    /// Write the peer id and dialable addresses as JSON to this file once the node listens, e.g. for a supervisor
+    /// to wait on. Removed on shutdown.
+    #[arg(long, value_name = "FILE")]
+    pub ready_file: Option<PathBuf>,
+
+    /// Security handshake for tcp connections. QUIC always uses its built-in TLS 1.3.
+    #[arg(long, value_enum, default_value_t = Security::Noise)]
+    pub security: Security,
+
+    /// Print the bandwidth used so far every N seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub bandwidth_report_interval: Option<u64>,
+
+    /// Also print loopback listen addresses, which only peers on this host can dial.
+    #[arg(long)]
+    pub include_loopback: bool,
+
+    /// Milliseconds to collect the listen addresses reported at startup before printing them as one summary.
+    /// 0 prints every address as it is reported.
+    #[arg(long, value_name = "MILLIS", default_value_t = 500)]
+    pub listen_settle: u64,
+
+    /// Maximum length of a stdin line in bytes. Longer lines are cut off.
+    #[arg(long, default_value_t = 65536, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_line_length: u64,
+
+    /// Seconds a shutdown on Ctrl-C or SIGTERM may take before the process exits regardless.
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub shutdown_grace: u64,
+
+    /// Prefix of stdin commands. Double it to send a line starting with it as a message.
+    #[arg(long, default_value = "/", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    pub command_prefix: String,
+
+    /// File of peers to dial at startup, one multiaddr per line. Blank lines and # comments are ignored.
+    #[arg(long, value_name = "FILE")]
+    pub peers_file: Option<PathBuf>,
+
+    /// Maximum number of startup dials in flight at once.
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+    pub dial_concurrency: u64,
+
+    /// Port to listen on for tcp and quic, 0 for one picked by the OS.
+    #[arg(long, default_value_t = 0)]
+    pub listen_port: u16,
+
+    /// What to do when --listen-port is already in use.
+    #[arg(long, value_enum, default_value_t = PortConflict::Fail)]
+    pub on_port_conflict: PortConflict,
+
+    /// Maximum number of concurrent yamux streams per tcp connection.
+    #[arg(long, default_value_t = 512, value_parser = clap::value_parser!(u32).range(1..))]
+    pub yamux_max_streams: u32,
+}
+
+impl CommonOpts {
+    //yamux grows each stream's receive window on its own as throughput demands, so only the stream count is tunable.
+    pub fn yamux_config(&self) -> yamux::Config {
+        let mut config = yamux::Config::default();
+        config.set_max_num_streams(self.yamux_max_streams as usize);
+        config
+    }
+}
+
+//valid with or without a pre-shared key, since the pnet handshake runs underneath either of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Security {
+    Noise,
+    Tls,
+}
+
+//gossipsub options, flattened into the binaries that run gossipsub.
+#[derive(Args, Debug)]
+pub struct GossipsubOpts {
+    /// Peer to always forward messages to, regardless of the mesh (repeatable).
+    #[arg(long = "explicit-peer", value_name = "PEER_ID")]
+    pub explicit_peers: Vec<PeerId>,
+
+    /// Explicit peer given by its full address, e.g. /ip4/10.0.0.2/tcp/4001/p2p/<peer id>,
+    /// which is also dialed at startup (repeatable).
+    #[arg(long = "direct-peer", value_name = "MULTIADDR")]
+    pub direct_peers: Vec<Multiaddr>,
+
+    /// Treat each stdin line as the path of a file whose raw bytes are published.
+    #[arg(long)]
+    pub binary: bool,
+
+    /// Number of heartbeats a published message is kept in the message cache.
+    #[arg(long, default_value_t = 5)]
+    pub history_length: usize,
+
+    /// Number of most recent heartbeats whose messages are advertised in gossip, at most --history-length.
+    #[arg(long, default_value_t = 3)]
+    pub history_gossip: usize,
+
+    /// Seconds a message id is remembered to drop duplicates.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub duplicate_cache_time: u64,
+
+    /// Milliseconds between gossipsub heartbeats, the unit of --history-length and --history-gossip.
+    #[arg(long, value_name = "MILLIS", default_value_t = 1000)]
+    pub heartbeat_interval: u64,
+
+    /// Subscribe and print received messages, but never publish, e.g. for a logging sink.
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Leaf-only mode: receive messages but never relay them to other peers. Our own messages are still published.
+    #[arg(long)]
+    pub no_forward: bool,
+
+    /// Seconds the peers picked to receive messages for a topic we publish to without subscribing are remembered.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub fanout_ttl: u64,
+
+    /// Number of peers each topic's mesh aims for. Applies to every topic the node subscribes to.
+    #[arg(long, default_value_t = 6)]
+    pub mesh_n: usize,
+
+    /// Fewest mesh peers of a topic before more are grafted, at most --mesh-n.
+    #[arg(long, default_value_t = 5)]
+    pub mesh_n_low: usize,
+
+    /// Most mesh peers of a topic before some are pruned, at least --mesh-n.
+    #[arg(long, default_value_t = 12)]
+    pub mesh_n_high: usize,
+
+    /// Maximum number of message ids accepted from a peer in IHAVE gossip per heartbeat.
+    #[arg(long, default_value_t = 5000)]
+    pub max_ihave_length: usize,
+
+    /// Maximum number of IHAVE messages accepted from a peer per heartbeat.
+    #[arg(long, default_value_t = 10)]
+    pub max_ihave_messages: usize,
+
+    /// Compress published payloads. Compressed payloads from others are decompressed regardless.
+    #[arg(long, value_enum, default_value_t = Compression::None)]
+    pub compress: Compression,
+
+    /// Number published messages per sender session and log gaps and reordering in the numbers of received ones.
+    #[arg(long)]
+    pub track_sequence: bool,
+
+    /// Received messages waiting to be printed, beyond which --overflow applies.
+    #[arg(long, value_name = "MESSAGES", default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    pub display_queue: u64,
+
+    /// What to do with a received message when the display queue is full.
+    #[arg(long, value_enum, default_value_t = Overflow::Drop)]
+    pub overflow: Overflow,
+
+    /// Hold back published lines until the topic has a mesh peer, for at most this many seconds,
+    /// so the first messages after startup aren't lost while the mesh forms.
+    #[arg(long, value_name = "SECONDS")]
+    pub wait_for_mesh: Option<u64>,
+
+    /// Keep messages that reach no peer in this file and publish them once the topic has a mesh,
+    /// also after a restart.
+    #[arg(long, value_name = "FILE")]
+    pub outbound_queue: Option<PathBuf>,
+
+    /// Maximum number of messages in --outbound-queue. The oldest is dropped when it is full.
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub outbound_queue_size: u64,
+}
+
+//handling of a listen port that is already in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PortConflict {
+    Fail,
+    //try the following ports.
+    Next,
+    //let the OS pick a free port.
+    Random,
+}
+
+//the text form of peer ids in the output. both are accepted wherever a peer id is typed in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PeerIdFormat {
+    Base58,
+    //a CIDv1 with the libp2p-key codec in multibase base32, as used by IPNS and kubo's --ipns-base.
+    Base32,
+}
+
+//payload compression, trading CPU for bandwidth.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+//what happens to a received message when the display queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    //drop the oldest queued message.
+    Drop,
+    //stop handling network events until there is room, which pushes back on the peers sending to us.
+    Block,
+}
+
+impl GossipsubOpts {
+    //a gossipsub config builder with these options applied, rejecting inconsistent values.
+    pub fn config_builder(&self) -> Result<gossipsub::ConfigBuilder, String> {
+        if self.history_gossip > self.history_length {
+            return Err(format!(
+                "--history-gossip ({}) must not exceed --history-length ({})",
+                self.history_gossip, self.history_length
+            ));
+        }
+        if self.heartbeat_interval == 0 {
+            return Err("--heartbeat-interval must be positive".to_string());
+        }
+        if self.fanout_ttl == 0 {
+            return Err("--fanout-ttl must be positive".to_string());
+        }
+        if self.max_ihave_length == 0 || self.max_ihave_messages == 0 {
+            return Err("--max-ihave-length and --max-ihave-messages must be positive".to_string());
+        }
+        if !(1..=self.mesh_n).contains(&self.mesh_n_low) || self.mesh_n > self.mesh_n_high {
+            return Err(format!(
+                "expected 1 <= --mesh-n-low ({}) <= --mesh-n ({}) <= --mesh-n-high ({})",
+                self.mesh_n_low, self.mesh_n, self.mesh_n_high
+            ));
+        }
+
+        let mut builder = gossipsub::ConfigBuilder::default();
+        builder
+            .history_length(self.history_length)
+            .history_gossip(self.history_gossip)
+            .duplicate_cache_time(Duration::from_secs(self.duplicate_cache_time))
+            .heartbeat_interval(self.heartbeat_interval())
+            .fanout_ttl(Duration::from_secs(self.fanout_ttl))
+            .max_ihave_length(self.max_ihave_length)
+            .max_ihave_messages(self.max_ihave_messages)
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            //gossipsub requires at most half the mesh to be kept for outbound peers.
+            .mesh_outbound_min(self.mesh_n_low.min(self.mesh_n / 2).min(2));
+        if self.no_forward {
+            //messages are only forwarded once validated, see report_validation.
+            builder.validate_messages();
+        }
+        Ok(builder)
+    }
+
+    //the gossipsub config with `customize` applied on top of these options. gossipsub's own checks
+    //are reported with the options involved, before the swarm is built.
+    pub fn config(
+        &self,
+        customize: impl FnOnce(&mut gossipsub::ConfigBuilder),
+    ) -> Result<gossipsub::Config, String> {
+        let mut builder = self.config_builder()?;
+        customize(&mut builder);
+        builder.build().map_err(|e| {
+            let options = match e {
+                gossipsub::ConfigBuilderError::HistoryLengthTooSmall => {
+                    " (--history-length, --history-gossip)"
+                }
+                gossipsub::ConfigBuilderError::MeshParametersInvalid
+                | gossipsub::ConfigBuilderError::MeshOutboundInvalid => {
+                    " (--mesh-n, --mesh-n-low, --mesh-n-high)"
+                }
+                _ => "",
+            };
+            format!("invalid gossipsub configuration{options}: {e}")
+        })
+    }
+
+    //the --explicit-peer and --direct-peer peers, which are kept explicit whenever they reconnect.
+    pub fn configured_explicit_peers(&self) -> Vec<PeerId> {
+        self.explicit_peers
+            .iter()
+            .copied()
+            .chain(
+                self.direct_peers
+                    .iter()
+                    .filter_map(crate::utils::peer_id_of),
+            )
+            .collect()
+    }
+
+    //gossipsub 0.48 only has node-wide mesh parameters: every topic, whatever its rate, gets the same mesh size.
+    //logged for each topic subscribed to, so a low-rate control topic's mesh size isn't mistaken for its own setting.
+    pub fn log_topic_params(&self, topic: &gossipsub::TopicHash) {
+        info!(target: "play_p2p::gossipsub",
+            "{topic}: mesh of {} peers ({}..={}), shared by all topics",
+            self.mesh_n, self.mesh_n_low, self.mesh_n_high
+        );
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval)
+    }
+
+    //with --no-forward every received message is ignored rather than accepted, so it is still delivered to us but never relayed.
+    //the peer isn't penalized for it.
+    pub fn report_validation(
+        &self,
+        gossipsub: &mut gossipsub::Behaviour,
+        message_id: &gossipsub::MessageId,
+        propagation_source: &PeerId,
+    ) {
+        if self.no_forward {
+            let _ = gossipsub.report_message_validation_result(
+                message_id,
+                propagation_source,
+                gossipsub::MessageAcceptance::Ignore,
+            );
+        }
+    }
+
+    //the effective values, for logging at startup.
+    //a message is advertised in gossip for history_gossip heartbeats and served from the cache for history_length heartbeats,
+    //after which it is evicted and never re-gossiped.
+    pub fn summary(&self) -> String {
+        //in seconds as f64, so large values given on the command line can't overflow.
+        let heartbeat = self.heartbeat_interval().as_secs_f64();
+        let mode = match (self.read_only, self.no_forward) {
+            (false, false) => "",
+            (false, true) => ", forwarding disabled",
+            (true, false) => ", read-only",
+            (true, true) => ", read-only with forwarding disabled",
+        };
+        format!(
+            "history length {}, history gossip {}, duplicate cache time {}s, heartbeat {}ms \
+             (messages gossiped for {:.1}s, cached for {:.1}s), fanout ttl {}s, max IHAVE length {}, \
+             max IHAVE messages {}, mesh {} ({}..={}){mode}",
+            self.history_length,
+            self.history_gossip,
+            self.duplicate_cache_time,
+            self.heartbeat_interval,
+            heartbeat * self.history_gossip as f64,
+            heartbeat * self.history_length as f64,
+            self.fanout_ttl,
+            self.max_ihave_length,
+            self.max_ihave_messages,
+            self.mesh_n,
+            self.mesh_n_low,
+            self.mesh_n_high
+        )
+    }
+}
+
+//relay server options, for running the node as infrastructure other peers reserve relay slots on.
+#[derive(Args, Debug)]
+pub struct RelayOpts {
+    /// Act as a circuit relay server for other peers.
+    #[arg(long)]
+    pub relay_server: bool,
+
+    /// Maximum number of reservations the relay holds at once.
+    #[arg(long, default_value_t = 32)]
+    pub relay_max_reservations: usize,
+
+    /// Maximum number of reservations a single peer may hold.
+    #[arg(long, default_value_t = 2)]
+    pub relay_max_reservations_per_peer: usize,
+
+    /// Maximum number of circuits the relay carries at once.
+    #[arg(long, default_value_t = 8)]
+    pub relay_max_circuits: usize,
+
+    /// Maximum number of circuits a single peer may have through the relay.
+    #[arg(long, default_value_t = 2)]
+    pub relay_max_circuits_per_peer: usize,
+}
+
+impl RelayOpts {
+    pub fn config(&self) -> relay::Config {
+        relay::Config {
+            max_reservations: self.relay_max_reservations,
+            max_reservations_per_peer: self.relay_max_reservations_per_peer,
+            max_circuits: self.relay_max_circuits,
+            max_circuits_per_peer: self.relay_max_circuits_per_peer,
+            ..Default::default()
+        }
+    }
+}
+
+//parse the command line, with the options of a --config file placed before it, so built-in defaults are overridden by the
+//file and the file by the command line. repeatable options given in both places accumulate.
+//the binaries' parsers set args_override_self so an option may appear in both.
+pub fn parse_args<T: Parser>() -> Result<T, Box<dyn Error>> {
+    parse_args_from(env::args_os().collect())
+}
+
+fn parse_args_from<T: Parser>(mut args: Vec<OsString>) -> Result<T, Box<dyn Error>> {
+    if let Some(path) = config_path(&args) {
+        let file_args = config_file_args::<T>(&path)?;
+        args.splice(1..1, file_args);
+    }
+    let matches = switchable_flags(T::command()).get_matches_from(args);
+    Ok(T::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+//flags also take an explicit value, `--flag=false`, so the command line can turn off a flag the config file turns on.
+//a bare `--flag` still means true.
+fn switchable_flags(command: Command) -> Command {
+    command.mut_args(|arg| match arg.get_action() {
+        ArgAction::SetTrue => arg
+            .action(ArgAction::Set)
+            .num_args(0..=1)
+            .require_equals(true)
+            .value_parser(value_parser!(bool))
+            .default_value("false")
+            .hide_default_value(true)
+            .default_missing_value("true"),
+        _ => arg,
+    })
+}
+
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_str()?;
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+//the file's keys turned into command line arguments, rejecting keys that are not long options of the binary.
+//each value is attached with =, so one starting with a dash isn't taken for an option.
+fn config_file_args<T: Parser>(path: &Path) -> Result<Vec<OsString>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config file {}: {e}", path.display()))?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+
+    let command = T::command();
+    let unknown: Vec<&str> = table
+        .keys()
+        .map(String::as_str)
+        .filter(|key| {
+            *key == "config"
+                || !command
+                    .get_arguments()
+                    .any(|arg| arg.get_long() == Some(key))
+        })
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "unknown keys in config file {}: {}",
+            path.display(),
+            unknown.join(", ")
+        ));
+    }
+
+    let mut args = Vec::new();
+    for (key, value) in &table {
+        match value {
+            toml::Value::Array(values) => {
+                for value in values {
+                    args.push(format!("--{key}={}", config_value(key, value)?).into());
+                }
+            }
+            value => args.push(format!("--{key}={}", config_value(key, value)?).into()),
+        }
+    }
+    Ok(args)
+}
+
+fn config_value(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(text) => Ok(text.clone()),
+        toml::Value::Integer(number) => Ok(number.to_string()),
+        toml::Value::Float(number) => Ok(number.to_string()),
+        toml::Value::Boolean(flag) => Ok(flag.to_string()),
+        value => Err(format!(
+            "unsupported value for {key} in config file: {value}"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser, Debug)]
+    #[command(args_override_self = true)]
+    struct TestOpts {
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        #[arg(long)]
+        flag: bool,
+
+        #[arg(long)]
+        name: Option<String>,
+
+        #[arg(long)]
+        offset: Option<i64>,
+
+        #[arg(long = "peer")]
+        peers: Vec<String>,
+    }
+
+    //the options parsed from the command line args with a config file of the given contents.
+    fn parse(file: &str, args: &[&str]) -> TestOpts {
+        let path = env::temp_dir().join(format!("play-net-config-{}.toml", std::process::id()));
+        fs::write(&path, file).unwrap();
+        let mut command_line = vec![
+            OsString::from("test"),
+            "--config".into(),
+            path.clone().into(),
+        ];
+        command_line.extend(args.iter().map(OsString::from));
+        let opts = parse_args_from(command_line);
+        fs::remove_file(&path).unwrap();
+        opts.unwrap()
+    }
+
+    #[test]
+    fn file_values_are_attached_to_their_key() {
+        let path =
+            env::temp_dir().join(format!("play-net-config-args-{}.toml", std::process::id()));
+        fs::write(&path, "name = \"-dash\"\npeer = [\"a\", \"b\"]\n").unwrap();
+        let args = config_file_args::<TestOpts>(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(args.unwrap(), ["--name=-dash", "--peer=a", "--peer=b"]);
+
+        let opts = parse("name = \"-dash\"\noffset = -3\n", &[]);
+        assert_eq!(opts.name.as_deref(), Some("-dash"));
+        assert_eq!(opts.offset, Some(-3));
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        assert_eq!(
+            parse("name = \"file\"", &["--name", "cli"]).name.as_deref(),
+            Some("cli")
+        );
+        //repeatable options accumulate.
+        assert_eq!(parse("peer = [\"a\"]", &["--peer", "b"]).peers, ["a", "b"]);
+    }
+
+    #[test]
+    fn command_line_overrides_file_flags() {
+        assert!(!parse("", &[]).flag);
+        assert!(parse("flag = true", &[]).flag);
+        assert!(!parse("flag = true", &["--flag=false"]).flag);
+        assert!(parse("flag = false", &["--flag"]).flag);
+        assert!(parse("flag = false", &["--flag=true"]).flag);
+    }
+}
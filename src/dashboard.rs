@@ -0,0 +1,188 @@
+use crate::utils::{fmt_peer_id, json_string};
+use libp2p::{swarm::NetworkBehaviour, Multiaddr, PeerId, Swarm};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const RECENT_MESSAGES: usize = 50;
+
+//a single page polling /state.json, with no external assets. values are inserted as text, never as html.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>play-p2p node</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+h2 { margin-top: 1.5em; }
+td { padding: 0.2em 1em 0.2em 0; font-family: monospace; }
+</style>
+</head>
+<body>
+<h1>play-p2p node</h1>
+<h2>Listen addresses</h2>
+<table id="listen"></table>
+<h2>Connected peers</h2>
+<table id="peers"></table>
+<h2>Recent messages</h2>
+<table id="messages"></table>
+<script>
+function fill(id, rows) {
+  const table = document.getElementById(id);
+  table.replaceChildren(...rows.map(cells => {
+    const row = document.createElement("tr");
+    for (const cell of cells) {
+      const td = document.createElement("td");
+      td.textContent = cell;
+      row.appendChild(td);
+    }
+    return row;
+  }));
+}
+async function refresh() {
+  try {
+    const state = await (await fetch("/state.json")).json();
+    fill("listen", state.listen_addresses.map(address => [address]));
+    fill("peers", state.connected_peers.map(peer => [peer.peer_id, peer.rtt_ms === null ? "" : peer.rtt_ms + " ms"]));
+    fill("messages", state.messages.slice().reverse().map(message => [message.timestamp, message.source, message.text]));
+  } catch (e) {
+    console.error(e);
+  }
+}
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"#;
+
+//live node state shown by the dashboard, fed by the event loop.
+#[derive(Clone, Default)]
+pub struct Dashboard {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    listen_addresses: Vec<Multiaddr>,
+    connected_peers: Vec<PeerId>,
+    rtts: HashMap<PeerId, Duration>,
+    messages: VecDeque<Message>,
+}
+
+struct Message {
+    timestamp: String,
+    source: PeerId,
+    text: String,
+}
+
+impl Dashboard {
+    pub fn update<B: NetworkBehaviour>(&self, swarm: &Swarm<B>) {
+        let mut state = self.state.lock().unwrap();
+        state.listen_addresses = swarm.listeners().cloned().collect();
+        state.connected_peers = swarm.connected_peers().copied().collect();
+        state.rtts.retain(|peer_id, _| swarm.is_connected(peer_id));
+    }
+
+    pub fn message(&self, source: PeerId, text: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.messages.len() == RECENT_MESSAGES {
+            state.messages.pop_front();
+        }
+        state.messages.push_back(Message {
+            timestamp: crate::display::rfc3339_now(),
+            source,
+            text: text.to_string(),
+        });
+    }
+
+    pub fn rtt(&self, peer_id: PeerId, rtt: Duration) {
+        self.state.lock().unwrap().rtts.insert(peer_id, rtt);
+    }
+
+    fn json(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let listen_addresses = state
+            .listen_addresses
+            .iter()
+            .map(|address| json_string(&address.to_string()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let connected_peers = state
+            .connected_peers
+            .iter()
+            .map(|peer_id| {
+                let rtt = state
+                    .rtts
+                    .get(peer_id)
+                    .map(|rtt| rtt.as_millis().to_string())
+                    .unwrap_or_else(|| "null".to_string());
+                format!(
+                    "{{\"peer_id\":\"{peer_id}\",\"rtt_ms\":{rtt}}}",
+                    peer_id = fmt_peer_id(peer_id)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let messages = state
+            .messages
+            .iter()
+            .map(|message| {
+                format!(
+                    "{{\"timestamp\":\"{}\",\"source\":\"{}\",\"text\":{}}}",
+                    message.timestamp,
+                    fmt_peer_id(&message.source),
+                    json_string(&message.text)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"listen_addresses\":[{listen_addresses}],\"connected_peers\":[{connected_peers}],\"messages\":[{messages}]}}"
+        )
+    }
+}
+
+//bind the dashboard and serve the page and its state in the background.
+pub async fn spawn(addr: SocketAddr, dashboard: Dashboard) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Dashboard listening on http://{}", listener.local_addr()?);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(respond(stream, dashboard.clone()));
+                }
+                Err(e) => eprintln!("Dashboard accept error: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream, dashboard: Dashboard) {
+    let mut buf = [0u8; 1024];
+    let Ok(read) = stream.read(&mut buf).await else {
+        return;
+    };
+    //GET /state.json returns the state, any other request the page.
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let (content_type, body) = match request.split_whitespace().nth(1) {
+        Some("/state.json") => ("application/json", dashboard.json()),
+        _ => ("text/html; charset=utf-8", PAGE.to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
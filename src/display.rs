@@ -0,0 +1,229 @@
+//shared by the binaries, each of which only uses part of it.
+#![allow(dead_code)]
+
+use crate::{config::Overflow, utils::fmt_peer_id};
+use libp2p::{
+    gossipsub::{Message, MessageId},
+    PeerId,
+};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    io::{self, IsTerminal},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::Notify, time};
+
+//how often the number of messages dropped by a full display queue is reported.
+const DROP_REPORT_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+//received messages on their way to stdout. the queue is bounded, so a flood that printing can't keep up with
+//doesn't grow memory without bound: when it is full the oldest message is dropped, or the caller waits for room.
+#[derive(Clone)]
+pub struct DisplayQueue {
+    shared: Arc<Shared>,
+    overflow: Overflow,
+}
+
+struct Shared {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+    dropped: AtomicU64, //since the last report
+    line_added: Notify,
+    line_taken: Notify,
+}
+
+impl DisplayQueue {
+    //starts the tasks printing the queued lines and reporting drops.
+    pub fn spawn(capacity: usize, overflow: Overflow) -> Self {
+        let shared = Arc::new(Shared {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            dropped: AtomicU64::new(0),
+            line_added: Notify::new(),
+            line_taken: Notify::new(),
+        });
+
+        let printer = shared.clone();
+        tokio::spawn(async move {
+            loop {
+                let line = printer.lines.lock().unwrap().pop_front();
+                match line {
+                    Some(line) => {
+                        printer.line_taken.notify_one();
+                        println!("{line}");
+                    }
+                    None => printer.line_added.notified().await,
+                }
+            }
+        });
+
+        let reporter = shared.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval_at(
+                time::Instant::now() + DROP_REPORT_INTERVAL,
+                DROP_REPORT_INTERVAL,
+            );
+            loop {
+                interval.tick().await;
+                let dropped = reporter.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    println!(
+                        "Display queue full: dropped {dropped} received message(s) in the last {}s",
+                        DROP_REPORT_INTERVAL.as_secs()
+                    );
+                }
+            }
+        });
+
+        DisplayQueue { shared, overflow }
+    }
+
+    pub async fn push(&self, line: String) {
+        loop {
+            {
+                let mut lines = self.shared.lines.lock().unwrap();
+                if lines.len() < self.shared.capacity || self.overflow == Overflow::Drop {
+                    if lines.len() >= self.shared.capacity {
+                        lines.pop_front();
+                        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    lines.push_back(line);
+                    self.shared.line_added.notify_one();
+                    return;
+                }
+            }
+            self.shared.line_taken.notified().await;
+        }
+    }
+}
+
+//terminal output styling: colors are only used when enabled and stdout is a terminal, so piped output stays plain.
+pub struct Style {
+    color: bool,
+}
+
+impl Style {
+    pub fn new(no_color: bool) -> Self {
+        Style {
+            color: !no_color && io::stdout().is_terminal(),
+        }
+    }
+
+    //the peer id in a color derived from its hash, so each peer keeps the same color.
+    pub fn peer(&self, peer_id: &PeerId) -> String {
+        if !self.color {
+            return fmt_peer_id(peer_id);
+        }
+        let mut hasher = DefaultHasher::new();
+        peer_id.hash(&mut hasher);
+        let color = 31 + hasher.finish() % 6; //red, green, yellow, blue, magenta, cyan
+        format!("\x1b[{color}m{}\x1b[0m", fmt_peer_id(peer_id))
+    }
+
+    pub fn timestamp(&self) -> String {
+        let timestamp = rfc3339_now();
+        if self.color {
+            format!("\x1b[2m{timestamp}\x1b[0m") //dimmed
+        } else {
+            timestamp
+        }
+    }
+}
+
+//ids of the most recently printed messages, so a message delivered again over another propagation path is not printed twice.
+//only affects what is displayed, forwarding is left to gossipsub.
+pub struct SeenMessages {
+    capacity: usize,
+    order: VecDeque<MessageId>,
+    ids: HashSet<MessageId>,
+}
+
+impl SeenMessages {
+    pub fn new(capacity: usize) -> Self {
+        SeenMessages {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    //true the first time an id is seen, evicting the oldest id once the cache is full.
+    pub fn insert(&mut self, id: &MessageId) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.clone());
+        self.ids.insert(id.clone());
+        true
+    }
+}
+
+//current UTC time as RFC3339, e.g. 2024-05-01T12:30:45Z.
+pub fn rfc3339_now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    //civil date from days since the epoch (Howard Hinnant's days_from_civil inverse).
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+//a received payload for printing: text as is, anything that isn't valid UTF-8 as its length and first bytes in hex.
+pub fn payload(data: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(data) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => {
+            let preview = data
+                .iter()
+                .take(16)
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ellipsis = if data.len() > 16 { " ..." } else { "" };
+            Cow::Owned(format!(
+                "<binary, {} bytes: {preview}{ellipsis}>",
+                data.len()
+            ))
+        }
+    }
+}
+
+//who published a message. gossipsub doesn't hand out the signature, but with strict validation, as both gossipsub binaries
+//use, it only delivers messages whose signature checked out against their source.
+pub fn origin(message: &Message) -> String {
+    match message.source {
+        Some(source) => format!("{} (signature verified)", fmt_peer_id(&source)),
+        None => "anonymous (unsigned)".to_string(),
+    }
+}
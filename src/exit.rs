@@ -0,0 +1,77 @@
+//shared by the binaries, each of which only fails in some of these ways.
+#![allow(dead_code)]
+
+use std::{error::Error, fmt, process};
+
+//the process exit code of each kind of failure, so scripts can branch on why a binary failed.
+//clap already exits with 2 on invalid arguments, which the other configuration errors share.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    //invalid options, or a file they name can't be read.
+    Config = 2,
+    //listening, resolving or reaching a peer failed.
+    Network = 3,
+    //a peer to join the network through, given on the command line, could not be dialed.
+    Bootstrap = 4,
+    //a message could not be delivered.
+    Transfer = 5,
+    //the cleanup on shutdown did not finish within --shutdown-grace, so the process was ended anyway.
+    Interrupted = 6,
+}
+
+//an error of a known kind. any other error exits with 1.
+#[derive(Debug)]
+pub struct Failure {
+    kind: Kind,
+    message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Failure {}
+
+fn failure(kind: Kind, error: impl fmt::Display) -> Box<dyn Error> {
+    Box::new(Failure {
+        kind,
+        message: error.to_string(),
+    })
+}
+
+//for map_err, e.g. `.map_err(exit::config)?`.
+pub fn config(error: impl fmt::Display) -> Box<dyn Error> {
+    failure(Kind::Config, error)
+}
+
+pub fn network(error: impl fmt::Display) -> Box<dyn Error> {
+    failure(Kind::Network, error)
+}
+
+pub fn bootstrap(error: impl fmt::Display) -> Box<dyn Error> {
+    failure(Kind::Bootstrap, error)
+}
+
+pub fn transfer(error: impl fmt::Display) -> Box<dyn Error> {
+    failure(Kind::Transfer, error)
+}
+
+//ends the process with the exit code of a kind of failure, right away rather than returning from main, whose runtime
+//would wait for the pending stdin read.
+pub fn exit(kind: Kind) -> ! {
+    process::exit(kind as i32)
+}
+
+//reports the error a binary's run() ended with and exits with its code.
+pub fn finish(result: Result<(), Box<dyn Error>>) -> ! {
+    let Err(error) = result else {
+        process::exit(0);
+    };
+    eprintln!("Error: {error}");
+    match error.downcast_ref::<Failure>() {
+        Some(failure) => exit(failure.kind),
+        None => process::exit(1),
+    }
+}
@@ -0,0 +1,102 @@
+use crate::utils::json_string;
+use libp2p::{swarm::NetworkBehaviour, Multiaddr, Swarm};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+//swarm state reported by the health endpoint, refreshed by the event loop.
+#[derive(Clone)]
+pub struct Health {
+    state: Arc<Mutex<State>>,
+    started: Instant,
+    min_peers: usize,
+}
+
+#[derive(Default)]
+struct State {
+    listen_addresses: Vec<Multiaddr>,
+    connected_peers: usize,
+}
+
+impl Health {
+    pub fn new(min_peers: usize) -> Self {
+        Health {
+            state: Arc::default(),
+            started: Instant::now(),
+            min_peers,
+        }
+    }
+
+    pub fn update<B: NetworkBehaviour>(&self, swarm: &Swarm<B>) {
+        let mut state = self.state.lock().unwrap();
+        state.listen_addresses = swarm.listeners().cloned().collect();
+        state.connected_peers = swarm.connected_peers().count();
+    }
+
+    //healthy when at least one listener is active and enough peers are connected.
+    fn report(&self) -> (bool, String) {
+        let state = self.state.lock().unwrap();
+        let healthy = !state.listen_addresses.is_empty() && state.connected_peers >= self.min_peers;
+        let addresses = state
+            .listen_addresses
+            .iter()
+            .map(|address| json_string(&address.to_string()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let body = format!(
+            "{{\"healthy\":{healthy},\"listen_addresses\":[{addresses}],\"connected_peers\":{},\"min_peers\":{},\"uptime_secs\":{}}}",
+            state.connected_peers,
+            self.min_peers,
+            self.started.elapsed().as_secs()
+        );
+        (healthy, body)
+    }
+}
+
+//bind the health endpoint and answer every HTTP request with the current health in the background.
+pub async fn spawn(addr: SocketAddr, health: Health) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!(
+        "Health endpoint listening on http://{}",
+        listener.local_addr()?
+    );
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(respond(stream, health.clone()));
+                }
+                Err(e) => eprintln!("Health endpoint accept error: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream, health: Health) {
+    //the request itself is not inspected, any path returns the health report.
+    let mut buf = [0u8; 1024];
+    if stream.read(&mut buf).await.is_err() {
+        return;
+    }
+
+    let (healthy, body) = health.report();
+    let status = if healthy {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
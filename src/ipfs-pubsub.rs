@@ -1,143 +1,524 @@
+use clap::Parser;
 use dotenv::dotenv;
-use either::Either;
 use futures::prelude::*;
 use libp2p::{
-    core::transport::upgrade::Version,
-    gossipsub, identify, noise, ping,
-    pnet::{PnetConfig, PreSharedKey},
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, Transport,
+    allow_block_list, gossipsub, identify, identity, ping,
+    pnet::PreSharedKey,
+    relay,
+    swarm::{behaviour::toggle::Toggle, ListenError, NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
 };
-use std::{env, error::Error, str::FromStr};
-use tokio::{io, io::AsyncBufReadExt, select, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+};
+use tokio::{
+    select,
+    time::{self, Duration, Instant},
+};
+use tracing::{info, warn};
+mod bandwidth;
+mod compression;
+mod config;
+mod dashboard;
+mod display;
+mod exit;
+mod health;
+mod outbound_queue;
+mod sequence;
+mod transport;
 mod utils;
 
-//combines gossipsub, ping and identify.
+#[derive(Parser, Debug)]
+#[command(name = "ipfs-pubsub", args_override_self = true)]
+struct Opt {
+    #[command(flatten)]
+    common: config::CommonOpts,
+
+    #[command(flatten)]
+    gossipsub: config::GossipsubOpts,
+
+    #[command(flatten)]
+    relay: config::RelayOpts,
+
+    /// Peer trusted to report the address it sees us on through identify, which is registered as our external
+    /// address once enough of them agree (repeatable). A lightweight alternative to AutoNAT.
+    #[arg(long = "probe-peer", value_name = "PEER_ID")]
+    probe_peers: Vec<PeerId>,
+
+    /// Number of probe peers that must report the same address before it is registered.
+    #[arg(long, default_value_t = 1)]
+    probe_confirmations: usize,
+
+    /// Swarm key file of the private network, instead of the swarm.key in IPFS_PATH. Repeat it while rotating the key:
+    /// dials use the first key, and each key gets a listener of its own so peers on either key can connect.
+    #[arg(long = "swarm-key", value_name = "PATH")]
+    swarm_keys: Vec<PathBuf>,
+
+    /// DNS server to resolve /dns and /dnsaddr addresses with, instead of the system resolver.
+    #[arg(long, value_name = "IP:PORT")]
+    dns_server: Option<SocketAddr>,
+
+    /// Serve a web dashboard of peers, messages and listen addresses on this address, e.g. 127.0.0.1:8081.
+    #[arg(long)]
+    dashboard_addr: Option<SocketAddr>,
+
+    /// Publish this message once the topic has a mesh peer, then exit, e.g. to publish from a script. Stdin isn't read.
+    #[arg(long, value_name = "MESSAGE")]
+    publish: Option<String>,
+
+    /// Seconds --publish waits for a mesh peer before publishing anyway.
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    publish_wait: u64,
+
+    /// Check that this node works end to end without a second machine and exit: two nodes connected over loopback
+    /// pass a probe message back and forth.
+    #[arg(long)]
+    self_test: bool,
+
+    /// Check connectivity to the single --peer and exit: dial it, wait for identify and a ping, and report each step.
+    #[arg(long, requires = "peers", conflicts_with = "self_test")]
+    connect_test: bool,
+
+    /// Close connections that carried no gossipsub traffic for this many seconds. Connections to mesh peers,
+    /// explicit peers and peers with a relay reservation or circuit are kept. Idle connections stay open without it.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+
+    /// Print the concrete addresses a /dnsaddr address expands to and exit, e.g. /dnsaddr/bootstrap.libp2p.io.
+    #[arg(long, value_name = "MULTIADDR")]
+    resolve_dnsaddr: Option<Multiaddr>,
+
+    /// Node to dial (repeatable), e.g. /ip4/127.0.0.1/tcp/4001/p2p/<peer id>.
+    #[arg(long = "peer", value_name = "MULTIADDR", value_parser = utils::parse_multiaddr_arg)]
+    peers: Vec<Multiaddr>,
+
+    /// Topic to subscribe and publish to. Falls back to IPFS_TOPIC, then play-ipfs.
+    #[arg(value_name = "TOPIC")]
+    topic: Option<String>,
+
+    /// Same as the positional TOPIC, e.g. for a --config file.
+    #[arg(long = "topic", value_name = "TOPIC", conflicts_with = "topic")]
+    topic_option: Option<String>,
+}
+
+//combines gossipsub, ping and identify, plus a circuit relay server when enabled and the peers banned from connecting.
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
     identify: identify::Behaviour,
     ping: ping::Behaviour,
+    relay: Toggle<relay::Behaviour>,
+    blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
 }
 
+//the stdin commands, listed by the help command.
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "list these commands"),
+    ("bandwidth", "print the bytes sent and received so far"),
+    ("who", "list the peers subscribed to our topic"),
+    (
+        "peers",
+        "list our topic's mesh peers apart from the peers only subscribed to it",
+    ),
+    (
+        "latency",
+        "list the connected peers by average ping round-trip time",
+    ),
+    (
+        "resync",
+        "re-add the configured explicit peers, our subscriptions can't be re-sent without leaving the topic",
+    ),
+    (
+        "protocols <peer id>",
+        "list the protocols a connected peer advertised over identify",
+    ),
+    ("dial <multiaddr>", "dial a peer"),
+    ("disconnect <peer id>", "close the connections to a peer"),
+    (
+        "ban <peer id>",
+        "disconnect a peer and keep it from reconnecting",
+    ),
+    ("unban <peer id>", "allow a banned peer to connect again"),
+];
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
+    exit::finish(run().await)
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
+    //libp2p logs through tracing too, e.g. RUST_LOG=libp2p_gossipsub=debug shows peers exceeding the IHAVE limits.
+    utils::init_tracing();
+    let opt: Opt = config::parse_args().map_err(exit::config)?;
+    utils::set_peer_id_format(opt.common.peer_id_format);
+    if !opt.probe_peers.is_empty()
+        && !(1..=opt.probe_peers.len()).contains(&opt.probe_confirmations)
+    {
+        return Err(exit::config(format!(
+            "--probe-confirmations must be between 1 and the number of probe peers ({})",
+            opt.probe_peers.len()
+        )));
+    }
+
+    if let Some(address) = &opt.resolve_dnsaddr {
+        let resolved = transport::resolve_dnsaddr(address, opt.dns_server)
+            .await
+            .map_err(exit::network)?;
+        println!("{address} expands to {} address(es)", resolved.len());
+        return Ok(());
+    }
+
+    let local_key = utils::local_keypair(
+        opt.common.secret_key_seed,
+        opt.common.identity_file.as_deref(),
+        opt.common.rotate_identity,
+    )
+    .map_err(exit::config)?;
+    if opt.common.print_peer_id {
+        utils::print_peer_id(&local_key, &["/ip4/0.0.0.0/tcp/0"]);
+        return Ok(());
+    }
+    println!(
+        "Local peer id: {}",
+        utils::fmt_peer_id(&local_key.public().to_peer_id())
+    );
 
     //a PSK(PreSharedKey) or swarm.key secures private libp2p networks, allowing only nodes with the same PSK to join and communicate.
-    let pre_shared_key: Option<PreSharedKey> = utils::get_pre_shared_key()?
-        .map(|text| PreSharedKey::from_str(&text))
-        .transpose()?;
+    let key_texts: Vec<String> = if opt.swarm_keys.is_empty() {
+        utils::get_pre_shared_key()
+            .map_err(exit::config)?
+            .into_iter()
+            .collect()
+    } else {
+        opt.swarm_keys
+            .iter()
+            .map(|path| {
+                fs::read_to_string(path).map_err(|e| {
+                    exit::config(format!("failed to read swarm key {}: {e}", path.display()))
+                })
+            })
+            .collect::<Result<_, _>>()?
+    };
+    let swarm_keys = transport::SwarmKeys::new(
+        key_texts
+            .iter()
+            .map(|text| PreSharedKey::from_str(text))
+            .collect::<Result<_, _>>()
+            .map_err(exit::config)?,
+    );
 
-    if let Some(pre_shared_key) = pre_shared_key {
+    let private_network = swarm_keys.is_some();
+    let fingerprints = swarm_keys
+        .as_ref()
+        .map(transport::SwarmKeys::fingerprints)
+        .unwrap_or_default();
+    for fingerprint in &fingerprints {
+        println!("using swarm key with fingerprint: {fingerprint}");
+    }
+
+    if opt.self_test {
+        return self_test(&opt, swarm_keys).await;
+    }
+    if opt.connect_test {
+        return connect_test(&opt, local_key, swarm_keys).await;
+    }
+
+    if let Some(server) = opt.dns_server {
+        println!("Resolving DNS addresses through {server}");
+    }
+    let mut bandwidth = bandwidth::Bandwidth::default();
+    let mut swarm = build_swarm(&opt, local_key, swarm_keys.clone(), &mut bandwidth)?;
+
+    println!("Gossipsub {}", opt.gossipsub.summary());
+    if opt.relay.relay_server {
         println!(
-            "using swarm key with fingerprint: {}",
-            pre_shared_key.fingerprint()
+            "Relay server enabled: max {} reservations ({} per peer), max {} circuits ({} per peer)",
+            opt.relay.relay_max_reservations,
+            opt.relay.relay_max_reservations_per_peer,
+            opt.relay.relay_max_circuits,
+            opt.relay.relay_max_circuits_per_peer
         );
     }
 
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_other_transport(|key| {
-            let noise_config = noise::Config::new(key).unwrap();
-            let yamux_config = yamux::Config::default();
-
-            let base_transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
-            let maybe_encrypted = match pre_shared_key {
-                Some(pre_shared_key) => {
-                    //a private netowork using the PreSharedKey.
-                    Either::Left(base_transport.and_then(move |socket, _| {
-                        PnetConfig::new(pre_shared_key).handshake(socket)
-                    }))
-                }
-                //IPFS public network.
-                None => Either::Right(base_transport),
-            };
-
-            maybe_encrypted
-                .upgrade(Version::V1Lazy) //ensures compatibility with lazy connections
-                .authenticate(noise_config)
-                .multiplex(yamux_config)
-        })?
-        .with_dns()?
-        .with_behaviour(|key| {
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .max_transmit_size(262144)
-                .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
-            Ok(MyBehaviour {
-                gossipsub: gossipsub::Behaviour::new(
-                    gossipsub::MessageAuthenticity::Signed(key.clone()),
-                    gossipsub_config,
-                )?,
-                //identify protocol exchanges information/metadata to verify the other peer's identity
-                identify: identify::Behaviour::new(identify::Config::new(
-                    "/ipfs/0.1.0".into(),
-                    key.public(),
-                )),
-                ping: ping::Behaviour::new(ping::Config::new()),
-            })
-        })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
-        .build();
-
-    let topic_name = env::var("IPFS_TOPIC").unwrap_or("play-ipfs".to_string());
+    let topic_name = opt
+        .topic
+        .clone()
+        .or_else(|| opt.topic_option.clone())
+        .or_else(|| env::var("IPFS_TOPIC").ok())
+        .unwrap_or_else(|| "play-ipfs".to_string());
     let gossipsub_topic = gossipsub::IdentTopic::new(topic_name);
 
     swarm
         .behaviour_mut()
         .gossipsub
-        .subscribe(&gossipsub_topic)
-        .unwrap();
+        .subscribe(&gossipsub_topic)?;
     println!("Subscribing to {:#?}", gossipsub_topic);
+    opt.gossipsub.log_topic_params(&gossipsub_topic.hash());
 
     // dialling other nodes if specified
-    for to_dial in std::env::args().skip(1) {
-        let addr: Multiaddr = utils::parse_legacy_multiaddr(&to_dial)?;
-        swarm.dial(addr)?;
-        println!("Dialed {to_dial:?}")
+    let transports = utils::Transports {
+        tcp: true,
+        quic: false,
+        dns: true,
+    };
+    let mut dial_queue = utils::DialQueue::new(
+        utils::startup_peers(&opt.peers, opt.common.peers_file.as_deref()).map_err(exit::config)?,
+        opt.common.dial_concurrency as usize,
+        transports,
+    );
+    dial_queue.fill(&mut swarm);
+
+    //explicit peers always receive our messages; direct peers are explicit peers we also dial.
+    for peer_id in &opt.gossipsub.explicit_peers {
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(peer_id);
+    }
+    for address in &opt.gossipsub.direct_peers {
+        let peer_id = utils::peer_id_of(address).ok_or_else(|| {
+            exit::config(format!(
+                "direct peer {address} is missing its /p2p/<peer id>"
+            ))
+        })?;
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        utils::dial(&mut swarm, address.clone(), transports).map_err(exit::bootstrap)?;
+    }
+    if !opt.gossipsub.explicit_peers.is_empty() || !opt.gossipsub.direct_peers.is_empty() {
+        let explicit_peers: Vec<_> = opt
+            .gossipsub
+            .explicit_peers
+            .iter()
+            .map(utils::fmt_peer_id)
+            .collect();
+        println!(
+            "Explicit peers: [{}], direct peers: {:?}",
+            explicit_peers.join(", "),
+            opt.gossipsub.direct_peers
+        );
     }
 
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    //one listener per swarm key, see transport::SwarmKeys. only the first one uses --listen-port.
+    let mut key_listeners = HashMap::new(); //listener -> index of its swarm key
+    for index in 0..fingerprints.len().max(1) {
+        let port = if index == 0 {
+            opt.common.listen_port
+        } else {
+            0
+        };
+        let listener_id = transport::listen_on(
+            &mut swarm,
+            format!("/ip4/0.0.0.0/tcp/{port}").parse()?,
+            opt.common.on_port_conflict,
+        )
+        .map_err(exit::network)?;
+        key_listeners.insert(listener_id, index);
+    }
+    utils::announce_addresses(&mut swarm, &opt.common.announce_addresses);
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let health = health::Health::new(opt.common.health_min_peers);
+    if let Some(addr) = opt.common.health_addr {
+        health::spawn(addr, health.clone()).await?;
+    }
+    let dashboard = dashboard::Dashboard::default();
+    if let Some(addr) = opt.dashboard_addr {
+        dashboard::spawn(addr, dashboard.clone()).await?;
+    }
+    let mut bandwidth_report = utils::interval_secs(opt.common.bandwidth_report_interval);
+
+    let explicit_peers = opt.gossipsub.configured_explicit_peers();
+    let mut subscribers = utils::Subscribers::default();
+    let mut latencies = utils::Latencies::default();
+    let mut peer_protocols = utils::PeerProtocols::default();
+    let mut idle_reaper = utils::IdleReaper::new(opt.idle_timeout.map(Duration::from_secs));
+    let display_queue =
+        display::DisplayQueue::spawn(opt.gossipsub.display_queue as usize, opt.gossipsub.overflow);
+    let mut observed_addresses: HashMap<Multiaddr, HashSet<PeerId>> = HashMap::new(); //reported by probe peers
+    let mut sequencer = sequence::Sequencer::new(opt.gossipsub.track_sequence);
+    let mut sequence_tracker = sequence::Tracker::new(opt.gossipsub.track_sequence);
+    let mut mesh_wait = utils::MeshWait::new(opt.gossipsub.wait_for_mesh.map(Duration::from_secs));
+    let mut outbound_queue = outbound_queue::OutboundQueue::open(
+        opt.gossipsub.outbound_queue.clone(),
+        opt.gossipsub.outbound_queue_size as usize,
+    )
+    .map_err(exit::config)?;
+    let mut shutdown = utils::ShutdownSignal::listen();
+    let mut ready_file = utils::ReadyFile::new(opt.common.ready_file.clone());
+    let mut listen_log = utils::ListenLog::new(Duration::from_millis(opt.common.listen_settle));
+    let mut stdin = utils::StdinLines::new(opt.common.max_line_length as usize);
+    let publish_deadline = time::sleep(Duration::from_secs(opt.publish_wait));
+    tokio::pin!(publish_deadline);
+    let mut publish_wait_over = false;
 
     loop {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                if let Err(e) = swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .publish(gossipsub_topic.clone(), line.as_bytes())
-                {
-                    println!("Publish error: {e:?}");
+            _ = &mut publish_deadline, if opt.publish.is_some() && !publish_wait_over => publish_wait_over = true,
+            //not read by --publish, so the pending read doesn't hold the process open once it's done.
+            Ok(Some(line)) = stdin.next_line(), if opt.publish.is_none() => match utils::parse_input(&line, &opt.common.command_prefix) {
+                utils::Input::Command("help") => utils::print_help(&opt.common.command_prefix, COMMANDS),
+                utils::Input::Command("bandwidth") => println!("{}", bandwidth.report()),
+                utils::Input::Command("peers") => utils::print_topic_peers(&swarm.behaviour().gossipsub, &gossipsub_topic.hash()),
+                utils::Input::Command("who") => {
+                    let peers = subscribers.of(&gossipsub_topic.hash());
+                    println!("{} peer(s) subscribed to {gossipsub_topic}", peers.len());
+                    for peer_id in peers {
+                        println!("  {peer_id}", peer_id = utils::fmt_peer_id(&peer_id));
+                    }
                 }
+                utils::Input::Command("latency") => latencies.print(),
+                utils::Input::Command("resync") => {
+                    utils::resync_gossipsub(&mut swarm.behaviour_mut().gossipsub, &explicit_peers)
+                }
+                utils::Input::Command(command) => {
+                    if let Some(address) = command.strip_prefix("dial ") {
+                        //the outcome shows up as an established connection or a connection error.
+                        match utils::parse_legacy_multiaddr(address.trim()) {
+                            Ok(address) => match utils::dial(&mut swarm, address.clone(), transports) {
+                                Ok(true) => println!("Dialing {address}"),
+                                Ok(false) => {}
+                                Err(e) => println!("Failed to dial {address}: {e}"),
+                            },
+                            Err(e) => eprintln!("Invalid address {address}: {e}"),
+                        }
+                    } else if let Some(peer_id) = command.strip_prefix("protocols ") {
+                        match utils::parse_peer_id(peer_id) {
+                            Ok(peer_id) => peer_protocols.print(&peer_id),
+                            Err(e) => eprintln!("Invalid peer id {peer_id}: {e}"),
+                        }
+                    } else if !utils::handle_peer_command(&mut swarm, command, |behaviour| {
+                        (&mut behaviour.gossipsub, &mut behaviour.blocked)
+                    }) {
+                        utils::unknown_command(&opt.common.command_prefix, command);
+                    }
+                }
+                utils::Input::Message(_) if opt.gossipsub.read_only => println!("Read-only mode, not publishing"),
+                utils::Input::Message(message) if mesh_wait.waiting() => mesh_wait.hold(message),
+                utils::Input::Message(message) => outbound_queue.publish(&mut swarm.behaviour_mut().gossipsub, &gossipsub_topic, message, &opt.gossipsub, &mut sequencer),
             },
+            _ = mesh_wait.check() => {
+                for line in mesh_wait.ready(&swarm.behaviour().gossipsub, &gossipsub_topic.hash()) {
+                    outbound_queue.publish(&mut swarm.behaviour_mut().gossipsub, &gossipsub_topic, &line, &opt.gossipsub, &mut sequencer);
+                }
+            }
+            _ = outbound_queue.check() => outbound_queue.flush(&mut swarm.behaviour_mut().gossipsub, &gossipsub_topic),
+            _ = utils::tick(&mut bandwidth_report) => println!("{}", bandwidth.report()),
+            _ = listen_log.check() => listen_log.summarize(),
+            signal = shutdown.recv() => {
+                let listeners: Vec<_> = key_listeners.keys().copied().collect();
+                let grace = Duration::from_secs(opt.common.shutdown_grace);
+                utils::begin_shutdown(&mut swarm, signal, &listeners, grace, &ready_file);
+                break;
+            }
+            _ = idle_reaper.check() => {
+                let mesh_peers: HashSet<PeerId> = swarm.behaviour().gossipsub.all_mesh_peers().copied().collect();
+                let in_use = |peer_id: &PeerId| mesh_peers.contains(peer_id) || explicit_peers.contains(peer_id);
+                for (connection_id, peer_id) in idle_reaper.idle(in_use) {
+                    info!(target: "play_p2p::swarm", "Closing idle connection {connection_id:?} to {peer_id}", peer_id = utils::fmt_peer_id(&peer_id));
+                    swarm.close_connection(connection_id);
+                }
+            }
             event = swarm.select_next_some() => {
                 match event {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("Listening on {address:?}");
+                    SwarmEvent::NewListenAddr { listener_id, address } => {
+                        let local_peer_id = *swarm.local_peer_id();
+                        let index = key_listeners.get(&listener_id).copied().unwrap_or(0);
+                        if let Some(swarm_keys) = &swarm_keys {
+                            swarm_keys.listening(index, &address);
+                        }
+                        let key = match fingerprints.get(index) {
+                            Some(fingerprint) if fingerprints.len() > 1 => format!(" for swarm key {fingerprint}"),
+                            _ => String::new(),
+                        };
+                        let addresses = utils::dialable_addresses(&address, local_peer_id, opt.common.include_loopback);
+                        for address in &addresses {
+                            listen_log.listening(format!("{address}{key}"));
+                        }
+                        ready_file.listening(local_peer_id, addresses);
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received {
+                        peer_id,
+                        info,
+                        ..
+                    })) if opt.probe_peers.contains(&peer_id) => {
+                        peer_protocols.identified(peer_id, &info);
+                        info!(target: "play_p2p::identify", "probe: {peer_id} sees us at {}", info.observed_addr, peer_id = utils::fmt_peer_id(&peer_id));
+                        let reporters = observed_addresses.entry(info.observed_addr.clone()).or_default();
+                        if reporters.insert(peer_id) && reporters.len() == opt.probe_confirmations {
+                            info!(
+                                target: "play_p2p::identify",
+                                "probe: registering external address {} (confirmed by {} peer(s), last {peer_id})",
+                                info.observed_addr,
+                                reporters.len(), peer_id = utils::fmt_peer_id(&peer_id)
+                            );
+                            swarm.add_external_address(info.observed_addr);
+                        }
+                    }
+                    //a push is identify running again after the peer's protocols changed.
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Identify(
+                        identify::Event::Received { peer_id, info, .. } | identify::Event::Pushed { peer_id, info, .. },
+                    )) => {
+                        peer_protocols.identified(peer_id, &info);
+                        info!(target: "play_p2p::identify", "{peer_id} is {} with {} protocol(s)", info.agent_version, info.protocols.len(), peer_id = utils::fmt_peer_id(&peer_id));
                     }
                     SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => {
-                        println!("identify: {event:?}");
+                        info!(target: "play_p2p::identify", "{event:?}");
                     }
                     SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::GossipsubNotSupported {
                         peer_id,
                     })) => {
-                        println!("peer_id: {} does not support Gossipsub protocol", peer_id);
+                        info!(target: "play_p2p::gossipsub", "peer_id: {} does not support Gossipsub protocol", utils::fmt_peer_id(&peer_id));
                     }
                     SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                         propagation_source: peer_id,
                         message_id: id,
                         message,
                     })) => {
-                        println!(
-                            "Received message: {} with id: {} from peer: {:?}",
-                            String::from_utf8_lossy(&message.data),
-                            id,
-                            peer_id
-                        )
+                        idle_reaper.active(&peer_id);
+                        opt.gossipsub.report_validation(&mut swarm.behaviour_mut().gossipsub, &id, &peer_id);
+                        let (sequence, data) = sequence::split(&message.data);
+                        if let (Some(sequence), Some(source)) = (sequence, message.source) {
+                            sequence_tracker.received(source, sequence);
+                        }
+                        let data = compression::decompress(data);
+                        let text = display::payload(&data);
+                        display_queue
+                            .push(format!(
+                                "Received message: {} with id: {} from peer: {}, published by {}",
+                                text,
+                                id,
+                                utils::fmt_peer_id(&peer_id),
+                                display::origin(&message)
+                            ))
+                            .await;
+                        dashboard.message(message.source.unwrap_or(peer_id), &text);
                     }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Relay(event)) => match event {
+                        relay::Event::ReservationReqAccepted { src_peer_id, renewed } => {
+                            if !renewed {
+                                idle_reaper.pin(src_peer_id);
+                            }
+                            info!(target: "play_p2p::relay", "reservation granted to {src_peer_id} (renewed: {renewed})", src_peer_id = utils::fmt_peer_id(&src_peer_id));
+                        }
+                        relay::Event::ReservationTimedOut { src_peer_id } => {
+                            idle_reaper.unpin(&src_peer_id);
+                            info!(target: "play_p2p::relay", "reservation of {src_peer_id} timed out", src_peer_id = utils::fmt_peer_id(&src_peer_id));
+                        }
+                        relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                            idle_reaper.pin(src_peer_id);
+                            idle_reaper.pin(dst_peer_id);
+                            info!(target: "play_p2p::relay", "circuit established from {src_peer_id} to {dst_peer_id}", src_peer_id = utils::fmt_peer_id(&src_peer_id), dst_peer_id = utils::fmt_peer_id(&dst_peer_id));
+                        }
+                        relay::Event::CircuitClosed { src_peer_id, dst_peer_id, error } => {
+                            idle_reaper.unpin(&src_peer_id);
+                            idle_reaper.unpin(&dst_peer_id);
+                            info!(target: "play_p2p::relay", "circuit from {src_peer_id} to {dst_peer_id} closed (error: {error:?})", src_peer_id = utils::fmt_peer_id(&src_peer_id), dst_peer_id = utils::fmt_peer_id(&dst_peer_id));
+                        }
+                        event => info!(target: "play_p2p::relay", "{event:?}"),
+                    },
                     SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => {
                         match event {
                             ping::Event {
@@ -145,38 +526,375 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 result: Result::Ok(rtt),
                                 ..
                             } => {
-                                println!(
-                                    "ping: rtt to {} is {} ms",
-                                    peer.to_base58(),
+                                info!(
+                                    target: "play_p2p::ping",
+                                    "rtt to {} is {} ms",
+                                    utils::fmt_peer_id(&peer),
                                     rtt.as_millis()
                                 );
+                                dashboard.rtt(peer, rtt);
+                                latencies.record(peer, rtt);
                             }
                             ping::Event {
                                 peer,
                                 result: Result::Err(ping::Failure::Timeout),
                                 ..
                             } => {
-                                println!("ping: timeout to {}", peer.to_base58());
+                                info!(target: "play_p2p::ping", "timeout to {}", utils::fmt_peer_id(&peer));
                             }
                             ping::Event {
                                 peer,
                                 result: Result::Err(ping::Failure::Unsupported),
                                 ..
                             } => {
-                                println!("ping: {} does not support ping protocol", peer.to_base58());
+                                info!(target: "play_p2p::ping", "{} does not support ping protocol", utils::fmt_peer_id(&peer));
                             }
                             ping::Event {
                                 peer,
                                 result: Result::Err(ping::Failure:: Other { error }),
                                 ..
                             } => {
-                                println!("ping: ping::Failure with {}: {error}", peer.to_base58());
+                                info!(target: "play_p2p::ping", "ping::Failure with {}: {error}", utils::fmt_peer_id(&peer));
                             }
                         }
                     }
-                    connection_event => println!("{connection_event:?}"),
+                    SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                        if dial_queue.finished(connection_id, Ok(peer_id)) {
+                            dial_queue.fill(&mut swarm);
+                        }
+                        idle_reaper.established(connection_id, peer_id);
+                        let address = endpoint.get_remote_address();
+                        let key = swarm_keys
+                            .as_ref()
+                            .map(|swarm_keys| format!(", swarm key {}", swarm_keys.key_for(&endpoint).fingerprint()))
+                            .unwrap_or_default();
+                        info!(
+                            target: "play_p2p::swarm",
+                            "Connection established with {peer_id} at {address} using {}{key}",
+                            transport::security_of(address, opt.common.security), peer_id = utils::fmt_peer_id(&peer_id)
+                        );
+                        //a configured explicit peer may have been dropped as explicit while it was away.
+                        if explicit_peers.contains(&peer_id) {
+                            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        }
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                        idle_reaper.active(&peer_id);
+                        let count = subscribers.subscribed(peer_id, &topic);
+                        info!(target: "play_p2p::gossipsub", "{peer_id} subscribed to {topic} ({count} subscriber(s))", peer_id = utils::fmt_peer_id(&peer_id));
+                    }
+                    SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                        let count = subscribers.unsubscribed(&peer_id, &topic);
+                        info!(target: "play_p2p::gossipsub", "{peer_id} unsubscribed from {topic} ({count} subscriber(s))", peer_id = utils::fmt_peer_id(&peer_id));
+                    }
+                    event @ SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => {
+                        idle_reaper.closed(connection_id, &peer_id, num_established);
+                        if num_established == 0 {
+                            subscribers.disconnected(&peer_id);
+                            latencies.disconnected(&peer_id);
+                            peer_protocols.disconnected(&peer_id);
+                        }
+                        info!(target: "play_p2p::swarm", "{event:?}");
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id, connection_id, error, .. } => {
+                        if dial_queue.finished(connection_id, Err(&error)) {
+                            dial_queue.fill(&mut swarm);
+                        }
+                        transport::print_dial_error(peer_id, &error, private_network);
+                    }
+                    SwarmEvent::IncomingConnectionError { send_back_addr, error: ListenError::Transport(error), .. } => {
+                        match transport::connection_error_reason(&error, private_network) {
+                            Some(reason) => warn!(target: "play_p2p::swarm", "Connection from {send_back_addr} failed: {reason}"),
+                            None => warn!(target: "play_p2p::swarm", "Connection from {send_back_addr} failed: {error}"),
+                        }
+                    }
+                    //a behaviour turned the peer away, e.g. one on the block list.
+                    SwarmEvent::IncomingConnectionError { send_back_addr, error: ListenError::Denied { cause }, .. } => {
+                        warn!(target: "play_p2p::swarm", "Connection from {send_back_addr} denied: {cause}")
+                    }
+                    connection_event => info!(target: "play_p2p::swarm", "{connection_event:?}"),
+                }
+            }
+        }
+        health.update(&swarm);
+        dashboard.update(&swarm);
+
+        if let Some(message) = &opt.publish {
+            let meshed = publish_wait_over
+                || swarm
+                    .behaviour()
+                    .gossipsub
+                    .mesh_peers(&gossipsub_topic.hash())
+                    .next()
+                    .is_some();
+            if meshed && !mesh_wait.waiting() {
+                let payload = utils::outgoing_payload(message, opt.gossipsub.binary)
+                    .and_then(|payload| compression::compress(payload, opt.gossipsub.compress))
+                    .map(|payload| sequencer.frame(payload))?;
+                return publish_once(&mut swarm, gossipsub_topic, payload).await;
+            }
+        }
+    }
+
+    drop(ready_file);
+    utils::end_shutdown()
+}
+
+//the swarm of a node, shared by the node itself and --self-test.
+fn build_swarm(
+    opt: &Opt,
+    local_key: identity::Keypair,
+    swarm_keys: Option<transport::SwarmKeys>,
+    bandwidth: &mut bandwidth::Bandwidth,
+) -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
+    let gossipsub_config = opt
+        .gossipsub
+        .config(|builder| {
+            builder.max_transmit_size(262144);
+        })
+        .map_err(exit::config)?;
+    let builder = libp2p::SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_other_transport(|key| {
+            transport::tcp_transport(
+                key,
+                opt.common.security,
+                opt.common.yamux_config(),
+                swarm_keys,
+            )
+        })?;
+    //the system resolver unless a DNS server is given.
+    let (dns_config, dns_opts) = transport::dns_resolver(opt.dns_server)?;
+    let swarm = builder
+        .with_dns_config(dns_config, dns_opts)
+        .with_bandwidth_metrics(bandwidth.registry_mut())
+        .with_behaviour(|key| {
+            Ok(MyBehaviour {
+                gossipsub: gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config,
+                )?,
+                //identify protocol exchanges information/metadata to verify the other peer's identity
+                identify: identify::Behaviour::new(identify::Config::new(
+                    "/ipfs/0.1.0".into(),
+                    key.public(),
+                )),
+                ping: ping::Behaviour::new(ping::Config::new()),
+                relay: Toggle::from(
+                    opt.relay.relay_server.then(|| {
+                        relay::Behaviour::new(key.public().to_peer_id(), opt.relay.config())
+                    }),
+                ),
+                blocked: allow_block_list::Behaviour::default(),
+            })
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+        .build();
+    Ok(swarm)
+}
+
+//--self-test: two nodes built like the real one and connected over loopback. one publishes a probe,
+//the other echoes it back, and the probe's round trip is timed.
+async fn self_test(
+    opt: &Opt,
+    swarm_keys: Option<transport::SwarmKeys>,
+) -> Result<(), Box<dyn Error>> {
+    const PROBE: &[u8] = b"play-p2p self-test probe";
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    let topic = gossipsub::IdentTopic::new("play-p2p-self-test");
+    let mut echo = build_swarm(
+        opt,
+        identity::Keypair::generate_ed25519(),
+        swarm_keys.clone(),
+        &mut bandwidth::Bandwidth::default(),
+    )?;
+    let mut probe = build_swarm(
+        opt,
+        identity::Keypair::generate_ed25519(),
+        swarm_keys,
+        &mut bandwidth::Bandwidth::default(),
+    )?;
+    echo.behaviour_mut().gossipsub.subscribe(&topic)?;
+    probe.behaviour_mut().gossipsub.subscribe(&topic)?;
+    echo.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)?;
+
+    let deadline = time::sleep(TIMEOUT);
+    tokio::pin!(deadline);
+    let mut sent: Option<Instant> = None;
+    loop {
+        select! {
+            _ = &mut deadline => {
+                let stage = if sent.is_some() { "the probe was published but not echoed back" } else { "the nodes never subscribed to each other" };
+                println!("self-test: FAIL, {stage} within {TIMEOUT:?}");
+                return Err(exit::network("self-test failed"));
+            }
+            event = echo.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => probe.dial(address)?,
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                    echo.behaviour_mut().gossipsub.publish(topic.clone(), message.data)?;
+                }
+                _ => {}
+            },
+            event = probe.select_next_some() => match event {
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { .. })) if sent.is_none() => {
+                    probe.behaviour_mut().gossipsub.publish(topic.clone(), PROBE)?;
+                    sent = Some(Instant::now());
                 }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) if message.data == PROBE => {
+                    let round_trip = sent.map(|sent| sent.elapsed()).unwrap_or_default();
+                    println!("self-test: PASS, probe echoed back in {round_trip:?}");
+                    return Ok(());
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+//--connect-test: a one-shot diagnostic of the connection to a peer, reporting each step so a failure can be pinpointed:
+//the connection and its transport, the peer id, the protocols announced through identify, and the ping rtt.
+async fn connect_test(
+    opt: &Opt,
+    local_key: identity::Keypair,
+    swarm_keys: Option<transport::SwarmKeys>,
+) -> Result<(), Box<dyn Error>> {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    let [address] = opt.peers.as_slice() else {
+        return Err(exit::config("--connect-test checks a single --peer"));
+    };
+    let private_network = swarm_keys.is_some();
+    let mut swarm = build_swarm(
+        opt,
+        local_key,
+        swarm_keys,
+        &mut bandwidth::Bandwidth::default(),
+    )?;
+    let transports = utils::Transports {
+        tcp: true,
+        quic: false,
+        dns: true,
+    };
+    if let Err(e) = utils::dial(&mut swarm, address.clone(), transports) {
+        println!("connect-test: dial: FAIL, {e}");
+        return Err(exit::network("connect-test failed"));
+    }
+    println!("connect-test: dialing {address}");
+
+    let deadline = time::sleep(TIMEOUT);
+    tokio::pin!(deadline);
+    let mut connected: Option<PeerId> = None;
+    let mut identified = false;
+    let mut pinged = false;
+    loop {
+        if identified && pinged {
+            if let Some(peer_id) = connected {
+                let _ = swarm.disconnect_peer_id(peer_id);
             }
+            println!("connect-test: PASS");
+            return Ok(());
+        }
+        select! {
+            _ = &mut deadline => {
+                let pending = match (connected, identified) {
+                    (None, _) => "connection",
+                    (Some(_), false) => "identify",
+                    (Some(_), true) => "ping",
+                };
+                println!("connect-test: {pending}: FAIL, no answer within {TIMEOUT:?}");
+                return Err(exit::network("connect-test failed"));
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } if connected.is_none() => {
+                    let remote = endpoint.get_remote_address();
+                    println!(
+                        "connect-test: connection: OK, {remote} using tcp with {}",
+                        transport::security_of(remote, opt.common.security)
+                    );
+                    match utils::peer_id_of(address) {
+                        Some(expected) if expected == peer_id => println!("connect-test: peer id: OK, {peer_id}", peer_id = utils::fmt_peer_id(&peer_id)),
+                        Some(expected) => {
+                            println!("connect-test: peer id: FAIL, {peer_id} answered instead of {expected}", peer_id = utils::fmt_peer_id(&peer_id), expected = utils::fmt_peer_id(&expected));
+                            return Err(exit::network("connect-test failed"));
+                        }
+                        None => println!("connect-test: peer id: not checked, the address has no /p2p part, {peer_id} answered", peer_id = utils::fmt_peer_id(&peer_id)),
+                    }
+                    connected = Some(peer_id);
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                    println!("connect-test: connection: FAIL");
+                    transport::print_dial_error(peer_id, &error, private_network);
+                    return Err(exit::network("connect-test failed"));
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }))
+                    if Some(peer_id) == connected && !identified =>
+                {
+                    println!(
+                        "connect-test: identify: OK, {} ({}), {} protocol(s)",
+                        info.agent_version,
+                        info.protocol_version,
+                        info.protocols.len()
+                    );
+                    for protocol in &info.protocols {
+                        println!("  {protocol}");
+                    }
+                    identified = true;
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Error { peer_id, error, .. }))
+                    if Some(peer_id) == connected =>
+                {
+                    println!("connect-test: identify: FAIL, {error}");
+                    return Err(exit::network("connect-test failed"));
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Ping(ping::Event { peer, result, .. }))
+                    if Some(peer) == connected && !pinged =>
+                {
+                    match result {
+                        Ok(rtt) => println!("connect-test: ping: OK, rtt {} ms", rtt.as_millis()),
+                        Err(e) => {
+                            println!("connect-test: ping: FAIL, {e}");
+                            return Err(exit::network("connect-test failed"));
+                        }
+                    }
+                    pinged = true;
+                }
+                SwarmEvent::ConnectionClosed { peer_id, cause, .. } if Some(peer_id) == connected => {
+                    let cause = cause.map(|cause| cause.to_string()).unwrap_or_else(|| "closed by the peer".to_string());
+                    println!("connect-test: connection: FAIL, closed before the test completed: {cause}");
+                    return Err(exit::network("connect-test failed"));
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+//publish a --publish message, then keep the swarm running for a moment so it actually leaves before the process exits.
+async fn publish_once(
+    swarm: &mut Swarm<MyBehaviour>,
+    topic: gossipsub::IdentTopic,
+    payload: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    match swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(topic.clone(), payload)
+    {
+        Ok(id) => utils::print_published(&swarm.behaviour().gossipsub, &topic.hash(), &id),
+        Err(e) => {
+            return Err(exit::transfer(format!(
+                "message not published, it likely reached nobody: {e:?}"
+            )))
+        }
+    }
+
+    let linger = time::sleep(Duration::from_secs(1));
+    tokio::pin!(linger);
+    loop {
+        select! {
+            _ = &mut linger => return Ok(()),
+            _ = swarm.select_next_some() => {}
         }
     }
 }
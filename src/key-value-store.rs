@@ -1,40 +1,122 @@
+use clap::{Parser, ValueEnum};
 use futures::stream::StreamExt;
 use libp2p::{
     kad,
-    kad::{store::MemoryStore, Mode},
-    mdns, noise,
+    kad::{
+        store::{MemoryStoreConfig, RecordStore},
+        Mode,
+    },
+    mdns,
     swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
-};
-use std::error::Error;
-use tokio::{
-    io::{self, AsyncBufReadExt},
-    select,
-    time::Duration,
+    Multiaddr, PeerId,
 };
+use record_store::PersistentStore;
+use std::{collections::HashSet, error::Error, path::PathBuf, time::Instant};
+use tokio::{select, time::Duration};
+use tracing::{info, warn};
+mod bandwidth;
+mod config;
+mod exit;
+mod health;
+mod record_store;
+mod transport;
+mod utils;
+
+#[derive(Parser, Debug)]
+#[command(name = "key-val-store", args_override_self = true)]
+struct Opt {
+    #[command(flatten)]
+    common: config::CommonOpts,
+
+    /// Where records and provider records are kept.
+    #[arg(long, value_enum, default_value_t = StoreKind::Memory)]
+    store: StoreKind,
+
+    /// Directory of the disk store.
+    #[arg(long, default_value = "kad-store")]
+    store_path: PathBuf,
+
+    /// Print the record a PUT would store instead of storing it.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+//the record ttl of kad's default config, which the kademlia behaviour is built with.
+const RECORD_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum StoreKind {
+    //records vanish on restart.
+    Memory,
+    //records are mirrored to --store-path and reloaded on startup.
+    Disk,
+}
 
 //combining mDNS and Kademlia allows nodes to function both locally and globally.
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
-    kademlia: kad::Behaviour<MemoryStore>,
+    kademlia: kad::Behaviour<PersistentStore>,
     mdns: mdns::tokio::Behaviour,
 }
 
+//the stdin commands, listed by the help command. DHT commands such as GET and PUT are typed without the prefix.
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "list these commands"),
+    ("bandwidth", "print the bytes sent and received so far"),
+    (
+        "add-address <peer id> <multiaddr>",
+        "teach the routing table an address of a peer",
+    ),
+    (
+        "remove-address <peer id> <multiaddr>",
+        "remove an address of a peer from the routing table",
+    ),
+];
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+async fn main() {
+    exit::finish(run().await)
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let opt: Opt = config::parse_args().map_err(exit::config)?;
+    utils::set_peer_id_format(opt.common.peer_id_format);
+    utils::init_tracing();
+
+    let local_key = utils::local_keypair(
+        opt.common.secret_key_seed,
+        opt.common.identity_file.as_deref(),
+        opt.common.rotate_identity,
+    )
+    .map_err(exit::config)?;
+    if opt.common.print_peer_id {
+        utils::print_peer_id(&local_key, &["/ip4/0.0.0.0/tcp/0"]);
+        return Ok(());
+    }
+    let local_peer_id = local_key.public().to_peer_id();
+    println!(
+        "Local peer id: {local_peer_id}",
+        local_peer_id = utils::fmt_peer_id(&local_peer_id)
+    );
+
+    let store = match opt.store {
+        StoreKind::Memory => PersistentStore::memory(local_peer_id),
+        StoreKind::Disk => {
+            println!("Persisting records in {}", opt.store_path.display());
+            PersistentStore::disk(local_peer_id, &opt.store_path).map_err(exit::config)?
+        }
+    };
+
+    let mut bandwidth = bandwidth::Bandwidth::default();
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
+        .with_other_transport(|key| {
+            transport::tcp_transport(key, opt.common.security, opt.common.yamux_config(), None)
+        })?
+        .with_bandwidth_metrics(bandwidth.registry_mut())
         .with_behaviour(|key| {
             Ok(MyBehaviour {
-                kademlia: kad::Behaviour::new(
-                    key.public().to_peer_id(),
-                    MemoryStore::new(key.public().to_peer_id()),
-                ),
+                kademlia: kad::Behaviour::new(key.public().to_peer_id(), store),
                 mdns: mdns::tokio::Behaviour::new(
                     mdns::Config::default(),
                     key.public().to_peer_id(),
@@ -47,37 +129,101 @@ async fn main() -> Result<(), Box<dyn Error>> {
     //Client Mode: nodes only handle incoming requests without participating in routing and forwarding requests to other peers.
     swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
 
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    let listener_id = transport::listen_on(
+        &mut swarm,
+        format!("/ip4/0.0.0.0/tcp/{}", opt.common.listen_port).parse()?,
+        opt.common.on_port_conflict,
+    )
+    .map_err(exit::network)?;
+    utils::announce_addresses(&mut swarm, &opt.common.announce_addresses);
+
+    let startup_peers =
+        utils::startup_peers(&[], opt.common.peers_file.as_deref()).map_err(exit::config)?;
+    //peers given with their /p2p part join the routing table right away, like those discovered over mDNS.
+    for (_, address) in &startup_peers {
+        if let Some(peer_id) = utils::peer_id_of(address) {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, address.clone());
+        }
+    }
+    let transports = utils::Transports {
+        tcp: true,
+        quic: false,
+        dns: false,
+    };
+    let mut dial_queue = utils::DialQueue::new(
+        startup_peers,
+        opt.common.dial_concurrency as usize,
+        transports,
+    );
+    dial_queue.fill(&mut swarm);
+
+    let health = health::Health::new(opt.common.health_min_peers);
+    if let Some(addr) = opt.common.health_addr {
+        health::spawn(addr, health.clone()).await?;
+    }
+    let mut bandwidth_report = utils::interval_secs(opt.common.bandwidth_report_interval);
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut shutdown = utils::ShutdownSignal::listen();
+    let mut ready_file = utils::ReadyFile::new(opt.common.ready_file.clone());
+    let mut listen_log = utils::ListenLog::new(Duration::from_millis(opt.common.listen_settle));
+    let mut stdin = utils::StdinLines::new(opt.common.max_line_length as usize);
     loop {
         select! {
-        Ok(Some(line)) = stdin.next_line() => {
-            handle_input_line(&mut swarm.behaviour_mut().kademlia, line);
+        Ok(Some(line)) = stdin.next_line() => match utils::parse_input(&line, &opt.common.command_prefix) {
+            utils::Input::Command("help") => utils::print_help(&opt.common.command_prefix, COMMANDS),
+            utils::Input::Command("bandwidth") => println!("{}", bandwidth.report()),
+            utils::Input::Command(command) => {
+                let local_peer_id = *swarm.local_peer_id();
+                if !handle_address_command(&mut swarm.behaviour_mut().kademlia, local_peer_id, command) {
+                    utils::unknown_command(&opt.common.command_prefix, command);
+                }
+            }
+            //DHT commands such as GET and PUT.
+            utils::Input::Message(line) => {
+                let local_peer_id = *swarm.local_peer_id();
+                handle_input_line(&mut swarm.behaviour_mut().kademlia, local_peer_id, opt.dry_run, line.to_string());
+            }
+        },
+        _ = utils::tick(&mut bandwidth_report) => println!("{}", bandwidth.report()),
+        _ = listen_log.check() => listen_log.summarize(),
+        signal = shutdown.recv() => {
+            let grace = Duration::from_secs(opt.common.shutdown_grace);
+            utils::begin_shutdown(&mut swarm, signal, &[listener_id], grace, &ready_file);
+            break;
         }
         event = swarm.select_next_some() => match event {
             SwarmEvent::NewListenAddr { address, .. } => {
-                println!("Listening in {address:?}");
+                let local_peer_id = *swarm.local_peer_id();
+                let addresses = utils::dialable_addresses(&address, local_peer_id, opt.common.include_loopback);
+                for address in &addresses {
+                    listen_log.listening(address.to_string());
+                }
+                ready_file.listening(local_peer_id, addresses);
             },
             SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                 for (peer_id, multiaddr) in list {
-                    println!("mDNS discovered a new peer: {peer_id} {multiaddr}");
+                    info!(target: "play_p2p::mdns", "discovered a new peer: {peer_id} {multiaddr}", peer_id = utils::fmt_peer_id(&peer_id));
                     //joining a node to the DHT where it can subsequently be discovered by all peers in the DHT.
                     swarm.behaviour_mut().kademlia.add_address(&peer_id, multiaddr);
                 }
             }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result, ..})) => {
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result, step, .. })) => {
                 match result {
                     kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers, .. })) => {
-                        for peer in providers {
-                            println!(
-                                "Peer {peer:?} provides key {:?}",
-                                std::str::from_utf8(key.as_ref()).unwrap()
-                            );
+                        let local_peer_id = *swarm.local_peer_id();
+                        print_providers(&mut swarm.behaviour_mut().kademlia, local_peer_id, &key, providers);
+                    }
+                    //each batch of providers found is a step of its own, so a query finishing in its first step found none.
+                    kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. })) => {
+                        if step.count.get() == 1 {
+                            info!(target: "play_p2p::kad", "No providers found for query {id:?}");
                         }
                     }
                     kad::QueryResult::GetProviders(Err(err)) => {
-                        eprintln!("Failed to get providers: {err:?}");
+                        warn!(target: "play_p2p::kad", "Failed to get providers: {err:?}");
                     }
                     kad::QueryResult::GetRecord(Ok(
                         kad::GetRecordOk::FoundRecord(kad::PeerRecord {
@@ -85,44 +231,311 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             ..
                         })
                     )) => {
-                        println!(
+                        info!(
+                            target: "play_p2p::kad",
                             "Got record {:?} {:?}",
-                            std::str::from_utf8(key.as_ref()).unwrap(),
-                            std::str::from_utf8(&value).unwrap(),
+                            String::from_utf8_lossy(key.as_ref()),
+                            String::from_utf8_lossy(&value),
                         );
                     }
                     kad::QueryResult::GetRecord(Ok(_)) => {}
                     kad::QueryResult::GetRecord(Err(err)) => {
-                        eprintln!("Failed to get record: {err:?}");
+                        warn!(target: "play_p2p::kad", "Failed to get record: {err:?}");
                     }
                     kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
-                        println!(
+                        info!(
+                            target: "play_p2p::kad",
                             "Successfully put record {:?}",
-                            std::str::from_utf8(key.as_ref()).unwrap()
+                            String::from_utf8_lossy(key.as_ref())
                         );
                     }
                     kad::QueryResult::PutRecord(Err(err)) => {
-                        eprintln!("Failed to put record: {err:?}");
+                        warn!(target: "play_p2p::kad", "Failed to put record: {err:?}");
                     }
                     kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
-                        println!(
+                        info!(
+                            target: "play_p2p::kad",
                             "Successfully put provider record {:?}",
-                            std::str::from_utf8(key.as_ref()).unwrap()
+                            String::from_utf8_lossy(key.as_ref())
                         );
                     }
                     kad::QueryResult::StartProviding(Err(err)) => {
-                        eprintln!("Failed to put provider record: {err:?}");
+                        warn!(target: "play_p2p::kad", "Failed to put provider record: {err:?}");
+                    }
+                    kad::QueryResult::GetClosestPeers(Ok(kad::GetClosestPeersOk { key, peers })) => {
+                        print_closest_peers(&mut swarm.behaviour_mut().kademlia, &key, &peers);
                     }
-                    bootstrap_event => println!("{bootstrap_event:?}"),
+                    kad::QueryResult::GetClosestPeers(Err(err)) => {
+                        warn!(target: "play_p2p::kad", "Failed to get closest peers: {err:?}");
+                    }
+                    bootstrap_event => info!(target: "play_p2p::kad", "{bootstrap_event:?}"),
+                }
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                if dial_queue.finished(connection_id, Ok(peer_id)) {
+                    dial_queue.fill(&mut swarm);
                 }
+                let address = endpoint.get_remote_address();
+                info!(
+                    target: "play_p2p::swarm",
+                    "Connection established with {peer_id} at {address} using {}",
+                    transport::security_of(address, opt.common.security), peer_id = utils::fmt_peer_id(&peer_id)
+                );
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, connection_id, error, .. } => {
+                if dial_queue.finished(connection_id, Err(&error)) {
+                    dial_queue.fill(&mut swarm);
+                }
+                transport::print_dial_error(peer_id, &error, false);
+            }
+            connection_event => info!(target: "play_p2p::swarm", "{connection_event:?}"),
+        }
+        }
+        health.update(&swarm);
+    }
+
+    match opt.store {
+        StoreKind::Memory => stop_providing_all(&mut swarm.behaviour_mut().kademlia),
+        //a disk store keeps its provider records, so the node provides them again on restart.
+        //it writes each change through as it happens, so there is nothing left to flush.
+        StoreKind::Disk => {
+            info!(target: "play_p2p::shutdown", "Keeping provider records in {}", opt.store_path.display())
+        }
+    }
+    drop(ready_file);
+    utils::end_shutdown()
+}
+
+//drop all our provider records on shutdown, so they are no longer republished and clients stop being pointed at this node.
+//copies already held by other peers are not revoked by kademlia, they expire on their own.
+fn stop_providing_all(kademlia: &mut kad::Behaviour<PersistentStore>) {
+    let keys: Vec<kad::RecordKey> = kademlia
+        .store_mut()
+        .provided()
+        .map(|record| record.key.clone())
+        .collect();
+    for key in keys {
+        kademlia.stop_providing(&key);
+        println!(
+            "Stopped providing {:?}",
+            String::from_utf8_lossy(key.as_ref())
+        );
+    }
+}
+
+//the neighborhood of a key in the DHT: right after joining, finding peers close to our own id confirms this node is findable.
+//peers are listed nearest first with their addresses, ready for manual dialing.
+fn print_closest_peers(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    key: &[u8],
+    peers: &[kad::PeerInfo],
+) {
+    let target = kad::KBucketKey::new(key.to_vec());
+    let target_name = PeerId::from_bytes(key)
+        .map(|peer_id| utils::fmt_peer_id(&peer_id))
+        .unwrap_or_else(|_| String::from_utf8_lossy(key).into_owned());
+    println!(
+        "Found {} distinct closest peers to {target_name}",
+        peers.len()
+    );
+
+    let mut peers: Vec<_> = peers
+        .iter()
+        .map(|peer| (kad::KBucketKey::from(peer.peer_id).distance(&target), peer))
+        .collect();
+    peers.sort_by_key(|(distance, _)| *distance);
+    for (distance, peer) in peers {
+        //log2 of the XOR distance, i.e. the index of the k-bucket the peer falls into relative to the key.
+        let distance = match distance.ilog2() {
+            Some(bucket) => format!("2^{bucket}"),
+            None => "0".to_string(),
+        };
+        //the query doesn't always return addresses, the routing table may still know some.
+        let addresses = if peer.addrs.is_empty() {
+            routing_table_addresses(kademlia, &peer.peer_id)
+        } else {
+            peer.addrs.clone()
+        };
+        if addresses.is_empty() {
+            println!(
+                "  {} at distance {distance}, no known addresses",
+                utils::fmt_peer_id(&peer.peer_id)
+            );
+        } else {
+            println!(
+                "  {} at distance {distance}",
+                utils::fmt_peer_id(&peer.peer_id)
+            );
+            for address in addresses {
+                println!("    {address}");
+            }
+        }
+    }
+}
+
+//the providers of a key with the addresses the routing table knows for them.
+fn print_providers(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    local_peer_id: PeerId,
+    key: &kad::RecordKey,
+    providers: HashSet<PeerId>,
+) {
+    println!(
+        "Found {} provider(s) of {}",
+        providers.len(),
+        String::from_utf8_lossy(key.as_ref())
+    );
+    for peer_id in providers {
+        if peer_id == local_peer_id {
+            println!(
+                "  {peer_id} (this node)",
+                peer_id = utils::fmt_peer_id(&peer_id)
+            );
+            continue;
+        }
+        let addresses = routing_table_addresses(kademlia, &peer_id);
+        if addresses.is_empty() {
+            println!(
+                "  {peer_id}, no known addresses",
+                peer_id = utils::fmt_peer_id(&peer_id)
+            );
+        } else {
+            println!("  {peer_id}", peer_id = utils::fmt_peer_id(&peer_id));
+            for address in addresses {
+                println!("    {address}");
             }
-            connection_event => println!("{connection_event:?}"),
         }
+    }
+}
+
+fn routing_table_addresses(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    peer_id: &PeerId,
+) -> Vec<Multiaddr> {
+    let mut addresses = Vec::new();
+    for bucket in kademlia.kbuckets() {
+        for entry in bucket.iter() {
+            if entry.node.key.preimage() == peer_id {
+                addresses.extend(entry.node.value.iter().cloned());
+            }
         }
     }
+    addresses
 }
 
-fn handle_input_line(kademlia: &mut kad::Behaviour<MemoryStore>, line: String) {
+//add-address and remove-address <peer_id> <multiaddr>, for peers learned out-of-band. false when the command is neither.
+fn handle_address_command(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    local_peer_id: PeerId,
+    command: &str,
+) -> bool {
+    let mut args = command.split_whitespace();
+    let add = match args.next() {
+        Some("add-address") => true,
+        Some("remove-address") => false,
+        _ => return false,
+    };
+    let (Some(peer_id), Some(address), None) = (args.next(), args.next(), args.next()) else {
+        eprintln!("Expected a peer id and a multiaddr");
+        return true;
+    };
+    let peer_id = match utils::parse_peer_id(peer_id) {
+        Ok(peer_id) => peer_id,
+        Err(e) => {
+            eprintln!("Invalid peer id {peer_id}: {e}");
+            return true;
+        }
+    };
+    let address = match address.parse::<Multiaddr>() {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("Invalid address {address}: {e}");
+            return true;
+        }
+    };
+    if peer_id == local_peer_id {
+        eprintln!(
+            "{peer_id} is our own peer id",
+            peer_id = utils::fmt_peer_id(&peer_id)
+        );
+        return true;
+    }
+    if let Some(other) = utils::peer_id_of(&address).filter(|other| *other != peer_id) {
+        eprintln!(
+            "{address} belongs to {other}, not {peer_id}",
+            peer_id = utils::fmt_peer_id(&peer_id),
+            other = utils::fmt_peer_id(&other)
+        );
+        return true;
+    }
+    //an address kademlia could never dial would only sit in the routing table.
+    let transports = utils::Transports {
+        tcp: true,
+        quic: false,
+        dns: false,
+    };
+    match utils::check_transports(&address, transports) {
+        Err(e) if add => {
+            eprintln!("Invalid address {address}: {e}");
+            return true;
+        }
+        _ => {}
+    }
+
+    if add {
+        match kademlia.add_address(&peer_id, address.clone()) {
+            kad::RoutingUpdate::Success => println!(
+                "Added {address} for {peer_id}",
+                peer_id = utils::fmt_peer_id(&peer_id)
+            ),
+            //the bucket is full: the peer is only inserted if its least recently seen peer turns out to be unreachable.
+            kad::RoutingUpdate::Pending => {
+                println!(
+                    "Bucket full, {peer_id} is added with {address} if a stale peer is evicted",
+                    peer_id = utils::fmt_peer_id(&peer_id)
+                )
+            }
+            kad::RoutingUpdate::Failed => println!(
+                "Failed to add {address} for {peer_id}",
+                peer_id = utils::fmt_peer_id(&peer_id)
+            ),
+        }
+    } else if kademlia.remove_address(&peer_id, &address).is_some() {
+        println!(
+            "Removed {address} for {peer_id}",
+            peer_id = utils::fmt_peer_id(&peer_id)
+        );
+    } else {
+        println!(
+            "{address} was not a known address of {peer_id}",
+            peer_id = utils::fmt_peer_id(&peer_id)
+        );
+    }
+
+    let addresses = routing_table_addresses(kademlia, &peer_id);
+    if addresses.is_empty() {
+        println!(
+            "{peer_id} has no known addresses, it is not in the routing table",
+            peer_id = utils::fmt_peer_id(&peer_id)
+        );
+    } else {
+        println!(
+            "Known addresses of {peer_id}:",
+            peer_id = utils::fmt_peer_id(&peer_id)
+        );
+    }
+    for address in addresses {
+        println!("  {address}");
+    }
+    true
+}
+
+fn handle_input_line(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    local_peer_id: PeerId,
+    dry_run: bool,
+    line: String,
+) {
     let mut args = line.split(' ');
 
     match args.next() {
@@ -158,23 +571,60 @@ fn handle_input_line(kademlia: &mut kad::Behaviour<MemoryStore>, line: String) {
                     }
                 }
             };
-            let value = match args.next() {
-                Some(value) => value.as_bytes().to_vec(),
-                None => {
-                    eprintln!("Expected value");
-                    return;
-                }
-            };
+            //the rest of the line is the value, so it may contain spaces.
+            let value = args.collect::<Vec<_>>().join(" ").into_bytes();
+            if value.is_empty() {
+                eprintln!("Expected value");
+                return;
+            }
+            let max_value_bytes = MemoryStoreConfig::default().max_value_bytes;
+            if value.len() > max_value_bytes {
+                eprintln!(
+                    "Value is {} bytes, the record store accepts at most {max_value_bytes} bytes",
+                    value.len()
+                );
+                return;
+            }
+            //put_record would fill in the same publisher and expiry, they are set here so a dry run shows them.
             let record = kad::Record {
                 key,
                 value,
-                publisher: None,
-                expires: None,
+                publisher: Some(local_peer_id),
+                expires: Some(Instant::now() + RECORD_TTL),
             };
+            if dry_run {
+                println!(
+                    "Dry run, not storing record:\n  key: {} (hex {})\n  value: {} bytes\n  publisher: {local_peer_id}\n  quorum: one\n  ttl: {}s",
+                    String::from_utf8_lossy(record.key.as_ref()),
+                    record
+                        .key
+                        .as_ref()
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect::<String>(),
+                    record.value.len(),
+                    RECORD_TTL.as_secs(), local_peer_id = utils::fmt_peer_id(&local_peer_id)
+                );
+                return;
+            }
             //stores a record in the DHT both locally and at nodes closest to the key, based on the XOR distance metric.
-            kademlia
-                .put_record(record, kad::Quorum::One)
-                .expect("Failed to store record locally.");
+            if let Err(err) = kademlia.put_record(record, kad::Quorum::One) {
+                eprintln!("Failed to store record locally: {err:?}");
+            }
+        }
+        Some("CLOSEST_PEERS") => {
+            //without an argument, look up the peers closest to our own id.
+            let peer_id = match args.next() {
+                Some(peer_id) => match utils::parse_peer_id(peer_id) {
+                    Ok(peer_id) => peer_id,
+                    Err(err) => {
+                        eprintln!("Invalid peer id {peer_id}: {err}");
+                        return;
+                    }
+                },
+                None => local_peer_id,
+            };
+            kademlia.get_closest_peers(peer_id);
         }
         Some("PUT_PROVIDER") => {
             let key = {
@@ -191,8 +641,25 @@ fn handle_input_line(kademlia: &mut kad::Behaviour<MemoryStore>, line: String) {
                 .start_providing(key)
                 .expect("Failed to start providing key");
         }
+        Some("STOP_PROVIDER") => {
+            let key = match args.next() {
+                Some(key) => kad::RecordKey::new(&key),
+                None => {
+                    eprintln!("Expected key");
+                    return;
+                }
+            };
+            //stop advertising the local node as a provider of the key.
+            kademlia.stop_providing(&key);
+            println!(
+                "Stopped providing {:?}",
+                String::from_utf8_lossy(key.as_ref())
+            );
+        }
         _ => {
-            eprintln!("expected GET, GET_PROVIDERS, PUT or PUT_PROVIDER");
+            eprintln!(
+                "expected GET, GET_PROVIDERS, PUT, PUT_PROVIDER, STOP_PROVIDER or CLOSEST_PEERS"
+            );
         }
     }
 }
@@ -0,0 +1,176 @@
+use crate::{compression, config::GossipsubOpts, sequence::Sequencer, utils};
+use libp2p::gossipsub;
+use std::{
+    collections::VecDeque,
+    fs, future, io,
+    path::{Path, PathBuf},
+};
+use tokio::time::{self, Duration, Instant};
+use tracing::{info, warn};
+
+//how often queued messages are tried again while the topic has no mesh.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+//--outbound-queue: published messages that reached no peer, kept on disk and published once the topic has a mesh,
+//also after a restart. gossipsub doesn't acknowledge messages, so a message counts as delivered once a mesh peer got it.
+pub struct OutboundQueue {
+    path: Option<PathBuf>, //none without --outbound-queue
+    capacity: usize,
+    messages: VecDeque<Vec<u8>>,
+    next_retry: Instant,
+}
+
+impl OutboundQueue {
+    //picks up the messages a previous run couldn't publish.
+    pub fn open(path: Option<PathBuf>, capacity: usize) -> Result<Self, String> {
+        let messages = match &path {
+            Some(path) => load(path)
+                .map_err(|e| format!("can't read --outbound-queue {}: {e}", path.display()))?,
+            None => VecDeque::new(),
+        };
+        if !messages.is_empty() {
+            println!(
+                "{} queued message(s) from a previous run, published once the topic has a mesh",
+                messages.len()
+            );
+        }
+        Ok(OutboundQueue {
+            path,
+            capacity,
+            messages,
+            next_retry: Instant::now(),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    //a payload that reached no peer, as published. the oldest message is dropped when the queue is full.
+    pub fn push(&mut self, topic: &gossipsub::IdentTopic, payload: Vec<u8>) {
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+            warn!(
+                target: "play_p2p::gossipsub",
+                "Dropped the oldest queued message for {topic}: outbound queue full ({} messages)",
+                self.capacity
+            );
+        }
+        self.messages.push_back(payload);
+        println!(
+            "No peer to publish to, message queued ({} queued)",
+            self.messages.len()
+        );
+        self.save();
+    }
+
+    //completes when queued messages are due for another try, never while the queue is empty,
+    //so it can sit in a select! branch.
+    pub async fn check(&self) {
+        if self.messages.is_empty() {
+            future::pending().await
+        } else {
+            time::sleep_until(self.next_retry).await
+        }
+    }
+
+    //publishes a stdin line, a file path with --binary, behind the messages already queued.
+    pub fn publish(
+        &mut self,
+        gossipsub: &mut gossipsub::Behaviour,
+        topic: &gossipsub::IdentTopic,
+        line: &str,
+        opts: &GossipsubOpts,
+        sequencer: &mut Sequencer,
+    ) {
+        let payload = match utils::outgoing_payload(line, opts.binary)
+            .and_then(|payload| compression::compress(payload, opts.compress))
+        {
+            Ok(payload) => sequencer.frame(payload),
+            Err(e) => return eprintln!("Failed to read {line}: {e}"),
+        };
+        //queued messages go out first, keeping the order they were published in,
+        //so while some are left the new one waits behind them.
+        self.flush(gossipsub, topic);
+        if !self.is_empty() {
+            return self.push(topic, payload);
+        }
+        match gossipsub.publish(topic.clone(), payload.clone()) {
+            Ok(id) => utils::print_published(gossipsub, &topic.hash(), &id),
+            Err(gossipsub::PublishError::InsufficientPeers) if self.enabled() => {
+                self.push(topic, payload)
+            }
+            Err(e) => println!("Publish error: {e:?}"),
+        }
+    }
+
+    //publishes the queued messages in order once the topic has a mesh peer, keeping those that still reach no one.
+    pub fn flush(&mut self, gossipsub: &mut gossipsub::Behaviour, topic: &gossipsub::IdentTopic) {
+        self.next_retry = Instant::now() + RETRY_INTERVAL;
+        if self.messages.is_empty() || gossipsub.mesh_peers(&topic.hash()).next().is_none() {
+            return;
+        }
+        let queued = self.messages.len();
+        while let Some(payload) = self.messages.pop_front() {
+            match gossipsub.publish(topic.clone(), payload.clone()) {
+                Ok(id) => utils::print_published(gossipsub, &topic.hash(), &id),
+                Err(gossipsub::PublishError::InsufficientPeers) => {
+                    self.messages.push_front(payload);
+                    break;
+                }
+                //an identical message already went out.
+                Err(gossipsub::PublishError::Duplicate) => {
+                    info!(target: "play_p2p::gossipsub", "Dropped a queued message for {topic}: already published")
+                }
+                Err(e) => {
+                    warn!(target: "play_p2p::gossipsub", "Dropped a queued message for {topic}: {e:?}")
+                }
+            }
+        }
+        if self.messages.len() != queued {
+            self.save();
+        }
+    }
+
+    //rewritten as a whole, so a crash leaves either the old or the new queue.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut contents = Vec::new();
+        for payload in &self.messages {
+            contents.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            contents.extend_from_slice(payload);
+        }
+        if let Err(e) = utils::write_atomically(path, contents) {
+            warn!(target: "play_p2p::gossipsub", "Failed to save the outbound queue to {}: {e}", path.display());
+        }
+    }
+}
+
+//each message is its length as a big-endian u32 followed by the payload. a missing file is an empty queue.
+fn load(path: &Path) -> io::Result<VecDeque<Vec<u8>>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e),
+    };
+    let mut messages = VecDeque::new();
+    let mut rest = contents.as_slice();
+    while !rest.is_empty() {
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated message");
+        let (length, after) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+        let length = u32::from_be_bytes(*length) as usize;
+        if after.len() < length {
+            return Err(truncated());
+        }
+        let (payload, after) = after.split_at(length);
+        messages.push_back(payload.to_vec());
+        rest = after;
+    }
+    Ok(messages)
+}
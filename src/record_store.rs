@@ -0,0 +1,462 @@
+use crate::utils::write_atomically;
+use libp2p::{
+    kad::{
+        store::{self, MemoryStore, RecordStore},
+        ProviderRecord, Record, RecordKey,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    borrow::Cow,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+//a kademlia record store kept in memory and, when given a directory, mirrored to disk so values and provider records survive restarts.
+//layout: <dir>/records/<key name> and <dir>/providers/<key name>/<provider peer id>, see key_name.
+pub struct PersistentStore {
+    memory: MemoryStore,
+    dir: Option<PathBuf>,
+}
+
+impl PersistentStore {
+    pub fn memory(local_id: PeerId) -> Self {
+        PersistentStore {
+            memory: MemoryStore::new(local_id),
+            dir: None,
+        }
+    }
+
+    //load the records persisted in the directory (creating it if needed), dropping the ones that expired meanwhile.
+    pub fn disk(local_id: PeerId, dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir.join("records"))?;
+        fs::create_dir_all(dir.join("providers"))?;
+        let mut memory = MemoryStore::new(local_id);
+
+        for entry in fs::read_dir(dir.join("records"))? {
+            let path = entry?.path();
+            match read_record(&path) {
+                Ok(Some(record)) => {
+                    if let Err(e) = memory.put(record) {
+                        eprintln!("Skipping persisted record {}: {e}", path.display());
+                    }
+                }
+                Ok(None) => remove_expired(&path),
+                Err(e) => eprintln!("Skipping persisted record {}: {e}", path.display()),
+            }
+        }
+
+        for key_entry in fs::read_dir(dir.join("providers"))? {
+            let key_dir = key_entry?.path();
+            //e.g. a stray file next to the key directories, which shouldn't keep the other providers from loading.
+            let entries = match fs::read_dir(&key_dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("Skipping persisted providers {}: {e}", key_dir.display());
+                    continue;
+                }
+            };
+            for entry in entries {
+                let path = entry?.path();
+                match read_provider(&path) {
+                    Ok(Some(record)) => {
+                        if let Err(e) = memory.add_provider(record) {
+                            eprintln!("Skipping persisted provider {}: {e}", path.display());
+                        }
+                    }
+                    Ok(None) => remove_expired(&path),
+                    Err(e) => eprintln!("Skipping persisted provider {}: {e}", path.display()),
+                }
+            }
+        }
+
+        Ok(PersistentStore {
+            memory,
+            dir: Some(dir.to_path_buf()),
+        })
+    }
+
+    fn record_path(&self, key: &RecordKey) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join("records").join(key_name(key)))
+    }
+
+    fn provider_path(&self, key: &RecordKey, provider: &PeerId) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(
+            dir.join("providers")
+                .join(key_name(key))
+                .join(provider.to_string()),
+        )
+    }
+}
+
+impl RecordStore for PersistentStore {
+    type RecordsIter<'a>
+        = <MemoryStore as RecordStore>::RecordsIter<'a>
+    where
+        Self: 'a;
+    type ProvidedIter<'a>
+        = <MemoryStore as RecordStore>::ProvidedIter<'a>
+    where
+        Self: 'a;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.memory.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        self.memory.put(r.clone())?;
+        if let Some(path) = self.record_path(&r.key) {
+            report(write_record(&path, &r), &path);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.memory.remove(k);
+        if let Some(path) = self.record_path(k) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.memory.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        self.memory.add_provider(record.clone())?;
+        if let Some(path) = self.provider_path(&record.key, &record.provider) {
+            report(write_provider(&path, &record), &path);
+        }
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.memory.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.memory.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.memory.remove_provider(k, p);
+        if let Some(path) = self.provider_path(k, p) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+//a failed write keeps the record in memory, it just won't survive a restart.
+fn report(result: io::Result<()>, path: &Path) {
+    if let Err(e) = result {
+        eprintln!("Failed to persist {}: {e}", path.display());
+    }
+}
+
+//a failed removal only means the expired entry is skipped again on the next start.
+fn remove_expired(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        eprintln!("Failed to remove expired {}: {e}", path.display());
+    }
+}
+
+//keys longer than this are hashed for their file name, so the name stays within the 255 bytes most file systems allow.
+const MAX_NAMED_KEY: usize = 127;
+const HASHED_KEY_PREFIX: &str = "long-";
+
+//the hex key, or for a long key a hash of it, with the hex key then kept as the first line of each of its files.
+fn key_name(key: &RecordKey) -> String {
+    let key = key.as_ref();
+    if key.len() <= MAX_NAMED_KEY {
+        return to_hex(key);
+    }
+    //FNV-1a, as the name has to stay the same across builds.
+    let hash = key
+        .iter()
+        .fold(0x6c62272e07bb014262b821756295c58d_u128, |hash, byte| {
+            (hash ^ u128::from(*byte)).wrapping_mul(0x0000000001000000000000000000013b)
+        });
+    format!("{HASHED_KEY_PREFIX}{hash:032x}")
+}
+
+//the key line a file starts with when its key name is hashed, see key_name.
+fn key_line(key: &RecordKey) -> String {
+    if key.as_ref().len() > MAX_NAMED_KEY {
+        format!("{}\n", to_hex(key.as_ref()))
+    } else {
+        String::new()
+    }
+}
+
+//the key of a file's key name, reading it from the first line of the contents when the name is hashed.
+fn read_key<'a>(name: &str, contents: &'a [u8]) -> Result<(RecordKey, &'a [u8]), String> {
+    if !name.starts_with(HASHED_KEY_PREFIX) {
+        let key = from_hex(name).ok_or("the file name is not a hex key")?;
+        return Ok((RecordKey::from(key), contents));
+    }
+    let end = contents
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or("missing key line")?;
+    let (line, rest) = (&contents[..end], &contents[end + 1..]);
+    let key = std::str::from_utf8(line)
+        .ok()
+        .and_then(from_hex)
+        .ok_or("invalid key line")?;
+    let key = RecordKey::from(key);
+    if key_name(&key) != name {
+        return Err("the key line doesn't match the file name".to_string());
+    }
+    Ok((key, rest))
+}
+
+//[<hex key>\n]<publisher>\n<expiry as unix seconds>\n<value bytes>, with empty lines for no publisher / no expiry.
+fn write_record(path: &Path, record: &Record) -> io::Result<()> {
+    let publisher = record
+        .publisher
+        .map(|peer_id| peer_id.to_string())
+        .unwrap_or_default();
+    let expires = record
+        .expires
+        .map(to_unix_secs)
+        .map(|secs| secs.to_string());
+    let mut contents = format!(
+        "{}{publisher}\n{}\n",
+        key_line(&record.key),
+        expires.unwrap_or_default()
+    )
+    .into_bytes();
+    contents.extend_from_slice(&record.value);
+    write_atomically(path, contents)
+}
+
+//none when the record has expired, an error when the file can't be read or parsed.
+fn read_record(path: &Path) -> Result<Option<Record>, String> {
+    let name = file_name(path)?;
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    let (key, contents) = read_key(name, &contents)?;
+    let mut parts = contents.splitn(3, |byte| *byte == b'\n');
+    let malformed = || "malformed record".to_string();
+    let publisher = match std::str::from_utf8(parts.next().ok_or_else(malformed)?) {
+        Ok("") => None,
+        Ok(publisher) => Some(PeerId::from_str(publisher).map_err(|e| e.to_string())?),
+        Err(_) => return Err(malformed()),
+    };
+    let Some(expires) = parse_expiry(parts.next().ok_or_else(malformed)?)? else {
+        return Ok(None);
+    };
+    let value = parts.next().ok_or_else(malformed)?.to_vec();
+    Ok(Some(Record {
+        key,
+        value,
+        publisher,
+        expires,
+    }))
+}
+
+//[<hex key>\n]<expiry as unix seconds>\n followed by one address per line.
+fn write_provider(path: &Path, record: &ProviderRecord) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let expires = record
+        .expires
+        .map(to_unix_secs)
+        .map(|secs| secs.to_string());
+    let mut contents = format!("{}{}\n", key_line(&record.key), expires.unwrap_or_default());
+    for address in &record.addresses {
+        contents.push_str(&format!("{address}\n"));
+    }
+    write_atomically(path, contents)
+}
+
+//none when the record has expired, an error when the file can't be read or parsed.
+fn read_provider(path: &Path) -> Result<Option<ProviderRecord>, String> {
+    let provider = PeerId::from_str(file_name(path)?).map_err(|e| e.to_string())?;
+    let key_dir = path.parent().ok_or("no key directory")?;
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    let (key, contents) = read_key(file_name(key_dir)?, &contents)?;
+    let contents = std::str::from_utf8(contents).map_err(|e| e.to_string())?;
+    let mut lines = contents.lines();
+    let Some(expires) = parse_expiry(lines.next().ok_or("malformed provider")?.as_bytes())? else {
+        return Ok(None);
+    };
+    let addresses = lines
+        .filter_map(|line| Multiaddr::from_str(line).ok())
+        .collect();
+    Ok(Some(ProviderRecord {
+        key,
+        provider,
+        expires,
+        addresses,
+    }))
+}
+
+fn file_name(path: &Path) -> Result<&str, String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| "not a UTF-8 file name".to_string())
+}
+
+//Ok(Some(None)) for no expiry, Ok(None) when the record has already expired.
+fn parse_expiry(line: &[u8]) -> Result<Option<Option<Instant>>, String> {
+    let malformed = || "malformed expiry".to_string();
+    match std::str::from_utf8(line).map_err(|_| malformed())? {
+        "" => Ok(Some(None)),
+        secs => {
+            let at = UNIX_EPOCH + Duration::from_secs(secs.parse().map_err(|_| malformed())?);
+            Ok(at
+                .duration_since(SystemTime::now())
+                .ok()
+                .map(|remaining| Some(Instant::now() + remaining)))
+        }
+    }
+}
+
+//instants only make sense within one process, so expiries are persisted as wall-clock time.
+fn to_unix_secs(expires: Instant) -> u64 {
+    let remaining = expires.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 == 1 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+
+    //a fresh directory under the system temp directory, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("play-net-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn peer_id() -> PeerId {
+        identity::Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    fn record(key: &[u8], value: &[u8], publisher: PeerId) -> Record {
+        Record {
+            key: RecordKey::new(&key),
+            value: value.to_vec(),
+            publisher: Some(publisher),
+            expires: Some(Instant::now() + Duration::from_secs(3600)),
+        }
+    }
+
+    fn provider(key: &[u8], provider: PeerId) -> ProviderRecord {
+        ProviderRecord {
+            key: RecordKey::new(&key),
+            provider,
+            expires: Some(Instant::now() + Duration::from_secs(3600)),
+            addresses: vec!["/ip4/10.0.0.1/tcp/4001".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn records_and_providers_survive_a_reopen() {
+        let dir = TempDir::new("reopen");
+        let (local, publisher, provider_id) = (peer_id(), peer_id(), peer_id());
+        //a key long enough to be stored under a hashed file name.
+        let long_key = vec![b'k'; MAX_NAMED_KEY + 1];
+        let mut store = PersistentStore::disk(local, &dir.0).unwrap();
+        store.put(record(b"key", b"value", publisher)).unwrap();
+        store.put(record(&long_key, b"long", publisher)).unwrap();
+        store.add_provider(provider(b"key", provider_id)).unwrap();
+        drop(store);
+
+        let store = PersistentStore::disk(local, &dir.0).unwrap();
+        let stored = store.get(&RecordKey::new(&b"key")).unwrap();
+        assert_eq!(stored.value, b"value");
+        assert_eq!(stored.publisher, Some(publisher));
+        assert!(stored.expires.is_some());
+        let stored = store.get(&RecordKey::new(&long_key)).unwrap();
+        assert_eq!(stored.value, b"long");
+        let providers = store.providers(&RecordKey::new(&b"key"));
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].provider, provider_id);
+        assert_eq!(
+            providers[0].addresses,
+            provider(b"key", provider_id).addresses
+        );
+    }
+
+    #[test]
+    fn removed_records_and_providers_stay_removed() {
+        let dir = TempDir::new("remove");
+        let (local, provider_id) = (peer_id(), peer_id());
+        let key = RecordKey::new(&b"key");
+        let mut store = PersistentStore::disk(local, &dir.0).unwrap();
+        store.put(record(b"key", b"value", local)).unwrap();
+        store.add_provider(provider(b"key", provider_id)).unwrap();
+        store.remove(&key);
+        store.remove_provider(&key, &provider_id);
+        drop(store);
+
+        let store = PersistentStore::disk(local, &dir.0).unwrap();
+        assert!(store.get(&key).is_none());
+        assert!(store.providers(&key).is_empty());
+    }
+
+    #[test]
+    fn leftovers_and_stray_files_are_skipped() {
+        let dir = TempDir::new("skip");
+        let (local, provider_id) = (peer_id(), peer_id());
+        let mut store = PersistentStore::disk(local, &dir.0).unwrap();
+        store.put(record(b"key", b"value", local)).unwrap();
+        store.add_provider(provider(b"key", provider_id)).unwrap();
+        drop(store);
+        let records = dir.0.join("records");
+        let key_dir = dir.0.join("providers").join(to_hex(b"key"));
+        //leftovers of interrupted writes, files whose name decodes to no key, and a file where a key directory belongs.
+        fs::write(
+            records.join(format!("{}.tmp", to_hex(b"key"))),
+            "half written",
+        )
+        .unwrap();
+        fs::write(records.join("not-a-key"), "\n\nvalue").unwrap();
+        fs::write(key_dir.join(format!("{provider_id}.tmp")), "half written").unwrap();
+        fs::write(key_dir.join("not-a-peer-id"), "\n").unwrap();
+        fs::write(dir.0.join("providers").join("stray"), "").unwrap();
+        //an expired record is dropped and its file removed.
+        let expired = records.join(to_hex(b"expired"));
+        fs::write(&expired, "\n1\nvalue").unwrap();
+
+        let store = PersistentStore::disk(local, &dir.0).unwrap();
+        assert_eq!(store.records().count(), 1);
+        assert_eq!(store.get(&RecordKey::new(&b"key")).unwrap().value, b"value");
+        assert_eq!(store.providers(&RecordKey::new(&b"key")).len(), 1);
+        assert!(!expired.exists());
+    }
+}
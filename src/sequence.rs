@@ -0,0 +1,102 @@
+use crate::utils::fmt_peer_id;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+//sequenced payloads start with this marker, the sender's session and the message's sequence number in that session.
+//like compression, anything else is a plain payload, so peers without --track-sequence can share a topic.
+const MAGIC: &[u8] = b"\0ps";
+const HEADER_LEN: usize = MAGIC.len() + 8 + 8;
+
+//numbers our published messages with --track-sequence. a restarted node starts a new session, counting from 0 again.
+pub struct Sequencer {
+    session: Option<u64>, //none without --track-sequence
+    next: u64,
+}
+
+impl Sequencer {
+    pub fn new(enabled: bool) -> Self {
+        let session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        Sequencer {
+            session: enabled.then_some(session),
+            next: 0,
+        }
+    }
+
+    //prefixes the payload with the next sequence number, applied after compression.
+    pub fn frame(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        let Some(session) = self.session else {
+            return payload;
+        };
+        let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+        framed.extend_from_slice(MAGIC);
+        framed.extend_from_slice(&session.to_be_bytes());
+        framed.extend_from_slice(&self.next.to_be_bytes());
+        framed.extend_from_slice(&payload);
+        self.next += 1;
+        framed
+    }
+}
+
+//the session and sequence number of a received payload, if it has them, and the payload without them.
+//received payloads are unframed whatever our own --track-sequence setting is.
+pub fn split(data: &[u8]) -> (Option<(u64, u64)>, &[u8]) {
+    match data.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 16 => {
+            let session = u64::from_be_bytes(rest[..8].try_into().unwrap());
+            let sequence = u64::from_be_bytes(rest[8..16].try_into().unwrap());
+            (Some((session, sequence)), &rest[16..])
+        }
+        _ => (None, data),
+    }
+}
+
+//the last sequence number seen from each publisher, to detect lost and reordered messages.
+pub struct Tracker {
+    enabled: bool,
+    publishers: HashMap<PeerId, (u64, u64)>, //session, highest sequence number
+}
+
+impl Tracker {
+    pub fn new(enabled: bool) -> Self {
+        Tracker {
+            enabled,
+            publishers: HashMap::new(),
+        }
+    }
+
+    pub fn received(&mut self, publisher: PeerId, (session, sequence): (u64, u64)) {
+        if !self.enabled {
+            return;
+        }
+        match self.publishers.get_mut(&publisher) {
+            Some((known_session, highest)) if *known_session == session => {
+                //sequence numbers come from the remote peer, so nothing here may overflow.
+                if sequence > *highest && sequence - *highest > 1 {
+                    warn!(target: "play_p2p::gossipsub",
+                        "detected {} missing messages from peer {publisher} (got {sequence} after {highest})",
+                        sequence - *highest - 1,
+                        publisher = fmt_peer_id(&publisher)
+                    );
+                } else if sequence <= *highest {
+                    warn!(target: "play_p2p::gossipsub",
+                        "message {sequence} from peer {publisher} arrived out of order, after {highest}",
+                        publisher = fmt_peer_id(&publisher)
+                    );
+                    return;
+                }
+                *highest = sequence;
+            }
+            //a first message, or a publisher that restarted: anything before it is not counted as missing.
+            _ => {
+                self.publishers.insert(publisher, (session, sequence));
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//shared by the binaries, each of which only uses part of it.
+#![allow(dead_code)]
+
+use crate::{
+    config::{PortConflict, Security},
+    utils::fmt_peer_id,
+};
+use either::Either;
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    ResolveError, TokioResolver,
+};
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{upgrade::Version, Boxed, ListenerId},
+        upgrade::NegotiationError,
+        ConnectedPoint,
+    },
+    identity,
+    multiaddr::Protocol,
+    noise,
+    pnet::{Fingerprint, PnetConfig, PnetError, PreSharedKey},
+    swarm::{DialError, NetworkBehaviour},
+    tcp, tls, yamux, Multiaddr, PeerId, Swarm, Transport, TransportError,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+//the swarm keys of a private network. more than one is only used while the key is rotated: dials and the first listener
+//use the first key, and each further key gets a listener of its own, so peers on either key can connect.
+//a single connection still runs exactly one key.
+#[derive(Clone)]
+pub struct SwarmKeys {
+    keys: Vec<PreSharedKey>,
+    listeners: Arc<Mutex<HashMap<u16, usize>>>, //listen port -> index of its key
+}
+
+impl SwarmKeys {
+    pub fn new(keys: Vec<PreSharedKey>) -> Option<Self> {
+        (!keys.is_empty()).then(|| SwarmKeys {
+            keys,
+            listeners: Default::default(),
+        })
+    }
+
+    pub fn fingerprints(&self) -> Vec<Fingerprint> {
+        self.keys.iter().map(PreSharedKey::fingerprint).collect()
+    }
+
+    //a listener for the key at this index is bound to the address.
+    pub fn listening(&self, index: usize, address: &Multiaddr) {
+        if let Some(port) = tcp_port(address) {
+            self.listeners.lock().unwrap().insert(port, index);
+        }
+    }
+
+    //the key of the listener an incoming connection arrived on, the first key for anything else.
+    pub fn key_for(&self, endpoint: &ConnectedPoint) -> PreSharedKey {
+        let index = match endpoint {
+            ConnectedPoint::Listener { local_addr, .. } => tcp_port(local_addr)
+                .and_then(|port| self.listeners.lock().unwrap().get(&port).copied())
+                .unwrap_or(0),
+            ConnectedPoint::Dialer { .. } => 0,
+        };
+        self.keys[index]
+    }
+}
+
+fn tcp_port(address: &Multiaddr) -> Option<u16> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::Tcp(port) => Some(port),
+        _ => None,
+    })
+}
+
+//tcp secured with the selected handshake and multiplexed with yamux.
+//with swarm keys the pnet handshake wraps the raw socket first, so both noise and tls compose with a private network.
+pub fn tcp_transport(
+    key: &identity::Keypair,
+    security: Security,
+    yamux: yamux::Config,
+    swarm_keys: Option<SwarmKeys>,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let base_transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
+    let maybe_encrypted = match swarm_keys {
+        Some(swarm_keys) => {
+            //a private netowork using the PreSharedKey.
+            Either::Left(base_transport.and_then(move |socket, endpoint| {
+                PnetConfig::new(swarm_keys.key_for(&endpoint)).handshake(socket)
+            }))
+        }
+        //IPFS public network.
+        None => Either::Right(base_transport),
+    };
+
+    let upgrade = maybe_encrypted.upgrade(Version::V1Lazy); //ensures compatibility with lazy connections
+    let transport = match security {
+        Security::Noise => upgrade
+            .authenticate(noise::Config::new(key)?)
+            .multiplex(yamux)
+            .boxed(),
+        Security::Tls => upgrade
+            .authenticate(tls::Config::new(key)?)
+            .multiplex(yamux)
+            .boxed(),
+    };
+    Ok(transport)
+}
+
+//a resolver config querying only the given DNS server, e.g. a private resolver that knows internal bootstrap hostnames.
+pub fn dns_config(server: SocketAddr) -> ResolverConfig {
+    ResolverConfig::from_parts(
+        None,
+        Vec::new(),
+        NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true),
+    )
+}
+
+//the resolver config and options of the dns transport: only the given DNS server, or the system configuration.
+pub fn dns_resolver(
+    server: Option<SocketAddr>,
+) -> Result<(ResolverConfig, ResolverOpts), ResolveError> {
+    match server {
+        Some(server) => Ok((dns_config(server), ResolverOpts::default())),
+        None => hickory_resolver::system_conf::read_system_conf(),
+    }
+}
+
+//nested /dnsaddr records followed at most, as libp2p's dns transport does.
+const MAX_DNSADDR_DEPTH: usize = 32;
+
+//--resolve-dnsaddr: expand a /dnsaddr address through its _dnsaddr TXT records the way the dns transport does before
+//dialing, printing each step and returning the concrete addresses, so dns based discovery can be checked before relying
+//on it. like the transport, only records ending in the rest of the address, e.g. its /p2p/<peer id>, are kept.
+pub async fn resolve_dnsaddr(
+    address: &Multiaddr,
+    dns_server: Option<SocketAddr>,
+) -> Result<Vec<Multiaddr>, String> {
+    if !matches!(address.iter().next(), Some(Protocol::Dnsaddr(_))) {
+        return Err(format!("{address} is not a /dnsaddr address"));
+    }
+    let resolver = match dns_server {
+        Some(server) => TokioResolver::tokio(dns_config(server), ResolverOpts::default()),
+        None => TokioResolver::tokio_from_system_conf()
+            .map_err(|e| format!("failed to read the system DNS configuration: {e}"))?,
+    };
+
+    let mut resolved = Vec::new();
+    let mut pending = vec![(address.clone(), 0)];
+    while let Some((address, depth)) = pending.pop() {
+        let indent = "  ".repeat(depth);
+        let Some(Protocol::Dnsaddr(name)) = address.iter().next() else {
+            println!("{indent}{address}");
+            resolved.push(address);
+            continue;
+        };
+        if depth == MAX_DNSADDR_DEPTH {
+            println!("{indent}{address}: not followed, more than {MAX_DNSADDR_DEPTH} nested /dnsaddr records");
+            continue;
+        }
+        let suffix: Multiaddr = address.iter().skip(1).collect();
+        let records = match resolver.txt_lookup(format!("_dnsaddr.{name}")).await {
+            Ok(records) => records,
+            Err(e) => {
+                println!("{indent}{address}: {}", dnsaddr_error_reason(&name, &e));
+                continue;
+            }
+        };
+        println!("{indent}{address}");
+        let mut matching = 0;
+        for record in records.iter() {
+            let text: String = record
+                .txt_data()
+                .iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect();
+            //other TXT records may share the name.
+            let Some(entry) = text.strip_prefix("dnsaddr=") else {
+                continue;
+            };
+            match entry.parse::<Multiaddr>() {
+                Ok(entry) if entry.ends_with(&suffix) => {
+                    matching += 1;
+                    pending.push((entry, depth + 1));
+                }
+                Ok(_) => {}
+                Err(e) => println!("{indent}  skipping invalid record {entry}: {e}"),
+            }
+        }
+        if matching == 0 {
+            println!("{indent}  no dnsaddr= TXT record at _dnsaddr.{name} matches {suffix}");
+        }
+    }
+    if resolved.is_empty() {
+        return Err(format!("{address} resolved to no concrete address"));
+    }
+    Ok(resolved)
+}
+
+fn dnsaddr_error_reason(name: &str, error: &ResolveError) -> String {
+    if error.is_nx_domain() {
+        format!("NXDOMAIN, _dnsaddr.{name} does not exist")
+    } else if error.is_no_records_found() {
+        format!("no TXT records at _dnsaddr.{name}")
+    } else {
+        format!("resolving _dnsaddr.{name} failed: {error}")
+    }
+}
+
+//the security protocol a connection to this address runs: quic always brings its own tls 1.3, tcp uses the selected one.
+pub fn security_of(address: &Multiaddr, security: Security) -> &'static str {
+    if address
+        .iter()
+        .any(|protocol| matches!(protocol, Protocol::QuicV1))
+    {
+        return "tls (quic)";
+    }
+    match security {
+        Security::Noise => "noise",
+        Security::Tls => "tls",
+    }
+}
+
+//a specific reason for a failed connection where the generic error is unhelpful, otherwise none.
+//a wrong swarm key doesn't fail the pnet handshake itself, which only swaps nonces: everything after it decrypts to garbage,
+//so the protocol negotiation that follows fails. in a private network that almost always means mismatched keys.
+pub fn connection_error_reason(
+    error: &(dyn Error + 'static),
+    private_network: bool,
+) -> Option<&'static str> {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(reason) = error
+            .downcast_ref::<io::Error>()
+            .and_then(|error| io_error_reason(error.kind()))
+        {
+            return Some(reason);
+        }
+        if error.downcast_ref::<PnetError>().is_some() {
+            return Some("pnet handshake failed");
+        }
+        if private_network && error.downcast_ref::<NegotiationError>().is_some() {
+            return Some(
+                "protocol negotiation failed after the pnet handshake, most likely a swarm key mismatch",
+            );
+        }
+        //the boxed transport wraps errors in io::Error, whose source() skips the wrapped error.
+        current = match error.downcast_ref::<io::Error>() {
+            Some(error) => error.get_ref().map(|inner| inner as &(dyn Error + 'static)),
+            None => error.source(),
+        };
+    }
+    None
+}
+
+fn io_error_reason(kind: io::ErrorKind) -> Option<&'static str> {
+    match kind {
+        io::ErrorKind::ConnectionRefused => {
+            Some("connection refused, check that the node is running and listening on that port")
+        }
+        io::ErrorKind::TimedOut => {
+            Some("timed out, check the firewall and that the address is reachable from here")
+        }
+        io::ErrorKind::ConnectionReset => Some("connection reset by the remote node"),
+        _ => None,
+    }
+}
+
+//prints an actionable message for a failed dial.
+//a dial that reached ourselves, e.g. through an address echoed back to us, is not reported.
+pub fn print_dial_error(peer_id: Option<PeerId>, error: &DialError, private_network: bool) {
+    let peer = peer_id.map_or_else(
+        || "the peer".to_string(),
+        |peer_id| format!("peer {}", fmt_peer_id(&peer_id)),
+    );
+    match error {
+        DialError::LocalPeerId { .. } => {}
+        DialError::NoAddresses => warn!(target: "play_p2p::swarm",
+            "No known addresses for {peer}: dial it with a full multiaddr, or wait for it to be discovered"
+        ),
+        DialError::WrongPeerId { obtained, endpoint } => warn!(target: "play_p2p::swarm",
+            "Wrong peer id at {address}: {obtained} answered instead of {peer}, verify the /p2p part of the multiaddr",
+            address = endpoint.get_remote_address(),
+            obtained = fmt_peer_id(obtained)
+        ),
+        DialError::Denied { cause } => {
+            warn!(target: "play_p2p::swarm", "Dial to {peer} denied: {cause}")
+        }
+        DialError::Transport(errors) => {
+            for (address, error) in errors {
+                match error {
+                    TransportError::MultiaddrNotSupported(_) => warn!(target: "play_p2p::swarm",
+                        "Transport error: {address} is not supported by any transport, verify the multiaddr"
+                    ),
+                    TransportError::Other(error) => {
+                        match connection_error_reason(error, private_network) {
+                            Some(reason) => {
+                                warn!(target: "play_p2p::swarm", "Transport error: {address} {reason}")
+                            }
+                            None => {
+                                warn!(target: "play_p2p::swarm", "Transport error: {address} {error}")
+                            }
+                        }
+                    }
+                }
+            }
+            if errors.len() > 1 {
+                warn!(target: "play_p2p::swarm", "All {} addresses for {peer} failed", errors.len());
+            }
+        }
+        error => warn!(target: "play_p2p::swarm", "Dial to {peer} failed: {error}"),
+    }
+}
+
+//ports tried after a taken one with --on-port-conflict next.
+const NEXT_PORT_ATTEMPTS: u16 = 16;
+
+//listen on the address, handling a taken port as --on-port-conflict says: fail, try the following ports, or let the OS pick one.
+pub fn listen_on<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    mut address: Multiaddr,
+    on_conflict: PortConflict,
+) -> Result<ListenerId, TransportError<io::Error>> {
+    let mut attempts = 0;
+    while let Some(port) = port_of(&address).filter(|port| *port != 0 && port_in_use(&address)) {
+        let next_port = match on_conflict {
+            PortConflict::Next if attempts < NEXT_PORT_ATTEMPTS && port < u16::MAX => port + 1,
+            PortConflict::Random => 0,
+            //let the transport report the taken port.
+            PortConflict::Fail | PortConflict::Next => break,
+        };
+        attempts += 1;
+        let next = with_port(&address, next_port);
+        println!("{address} is already in use, trying {next}");
+        address = next;
+    }
+    swarm.listen_on(address)
+}
+
+//whether the port of a tcp or udp listen address is taken, found by binding it briefly. the transports' own bind errors
+//come back wrapped in layers of transport errors, some of which hide the underlying io error.
+fn port_in_use(address: &Multiaddr) -> bool {
+    let mut protocols = address.iter();
+    let ip = match protocols.next() {
+        Some(Protocol::Ip4(ip)) => IpAddr::from(ip),
+        Some(Protocol::Ip6(ip)) => IpAddr::from(ip),
+        _ => return false,
+    };
+    let bound = match protocols.next() {
+        Some(Protocol::Tcp(port)) => std::net::TcpListener::bind((ip, port)).map(drop),
+        Some(Protocol::Udp(port)) => std::net::UdpSocket::bind((ip, port)).map(drop),
+        _ => return false,
+    };
+    matches!(bound, Err(e) if e.kind() == io::ErrorKind::AddrInUse)
+}
+
+//the tcp or udp port of the address.
+fn port_of(address: &Multiaddr) -> Option<u16> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::Tcp(port) | Protocol::Udp(port) => Some(port),
+        _ => None,
+    })
+}
+
+fn with_port(address: &Multiaddr, port: u16) -> Multiaddr {
+    address
+        .iter()
+        .map(|protocol| match protocol {
+            Protocol::Tcp(_) => Protocol::Tcp(port),
+            Protocol::Udp(_) => Protocol::Udp(port),
+            protocol => protocol,
+        })
+        .collect()
+}
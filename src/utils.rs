@@ -1,5 +1,36 @@
-use libp2p::{multiaddr::Protocol, Multiaddr};
-use std::{env, error::Error, fs, path::Path, str::FromStr};
+//shared by all binaries, each of which only uses a subset of these helpers.
+#![allow(dead_code)]
+
+use crate::{config::PeerIdFormat, exit};
+use libp2p::{
+    allow_block_list::{self, BlockedPeers},
+    core::transport::ListenerId,
+    gossipsub, identify, identity,
+    multiaddr::Protocol,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, DialError, NetworkBehaviour,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    error::Error,
+    fs, future,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader, Stdin},
+    signal,
+    time::{self, Duration, Instant, Interval},
+};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
 
 pub fn get_pre_shared_key() -> std::io::Result<Option<String>> {
     let ipfs_path: Box<Path> = env::var("IPFS_PATH")
@@ -14,33 +45,1758 @@ pub fn get_pre_shared_key() -> std::io::Result<Option<String>> {
     }
 }
 
-//parse a legacy multiaddr (replace ipfs with p2p), and strip the peer id so it can be dialed by rust-libp2p
+//network events are logged through tracing, each subsystem under its own target: play_p2p::swarm, ::gossipsub, ::ping,
+//::kad, ::mdns, ::identify and ::relay. e.g. RUST_LOG=play_p2p::ping=off mutes the pings.
+//play_p2p events are shown at info level unless RUST_LOG says otherwise about play_p2p.
+pub fn init_tracing() {
+    let directives = match env::var("RUST_LOG") {
+        Ok(directives) if directives.contains("play_p2p") => directives,
+        Ok(directives) => format!("play_p2p=info,{directives}"),
+        Err(_) => "play_p2p=info".to_string(),
+    };
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(directives))
+        .try_init();
+}
+
+//the --peer-id-format of this process, set once at startup.
+static PEER_ID_FORMAT: OnceLock<PeerIdFormat> = OnceLock::new();
+
+pub fn set_peer_id_format(format: PeerIdFormat) {
+    let _ = PEER_ID_FORMAT.set(format);
+}
+
+//multibase prefix, CID version and multicodec of a peer id as a base32 CIDv1.
+const CID_BASE32_PREFIX: char = 'b';
+const CID_V1: u8 = 0x01;
+const LIBP2P_KEY_CODEC: u8 = 0x72;
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+//a peer id in the --peer-id-format, used wherever one is printed.
+pub fn fmt_peer_id(peer_id: &PeerId) -> String {
+    fmt_peer_id_as(
+        peer_id,
+        PEER_ID_FORMAT
+            .get()
+            .copied()
+            .unwrap_or(PeerIdFormat::Base58),
+    )
+}
+
+fn fmt_peer_id_as(peer_id: &PeerId, format: PeerIdFormat) -> String {
+    match format {
+        PeerIdFormat::Base58 => peer_id.to_base58(),
+        PeerIdFormat::Base32 => {
+            let mut cid = vec![CID_V1, LIBP2P_KEY_CODEC];
+            cid.extend(peer_id.to_bytes());
+            format!("{CID_BASE32_PREFIX}{}", base32_encode(&cid))
+        }
+    }
+}
+
+//a peer id in either format, for peer ids typed in as command arguments.
+pub fn parse_peer_id(text: &str) -> Result<PeerId, String> {
+    let text = text.trim();
+    let Some(encoded) = text.strip_prefix(CID_BASE32_PREFIX) else {
+        return PeerId::from_str(text).map_err(|e| e.to_string());
+    };
+    let cid = base32_decode(encoded).ok_or("invalid base32")?;
+    match cid.as_slice() {
+        [CID_V1, LIBP2P_KEY_CODEC, multihash @ ..] => {
+            PeerId::from_bytes(multihash).map_err(|e| e.to_string())
+        }
+        _ => Err("not a libp2p-key CIDv1".to_string()),
+    }
+}
+
+//RFC 4648 base32, lowercase and unpadded as multibase uses it.
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+    encoded
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_lowercase())?;
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+//parse a legacy multiaddr (replace ipfs with p2p). a /p2p peer id is kept, so dials can skip our own peer and a peer
+//already being dialed.
+//the address is read component by component, so only the /ipfs protocol is renamed, never a value such as /dns/ipfs,
+//and an invalid address is reported with the component at fault.
 pub fn parse_legacy_multiaddr(text: &str) -> Result<Multiaddr, Box<dyn Error>> {
-    let sanitized = text
-        .split('/')
-        .map(|part| if part == "ipfs" { "p2p" } else { part })
-        .collect::<Vec<_>>()
-        .join("/");
-    let mut res = Multiaddr::from_str(&sanitized)?;
-    strip_peer_id(&mut res);
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("empty address".into());
+    }
+    let Some(rest) = text.strip_prefix('/') else {
+        return Err(format!("{text} doesn't start with /, e.g. /ip4/127.0.0.1/tcp/4001").into());
+    };
+
+    let mut res = Multiaddr::empty();
+    let mut segments = rest.strip_suffix('/').unwrap_or(rest).split('/');
+    while let Some(name) = segments.next() {
+        let name = match name {
+            "" => return Err(format!("{text} has an empty protocol name").into()),
+            "ipfs" => "p2p",
+            name => name,
+        };
+        //a unix path runs to the end of the address, slashes included.
+        if name == "unix" {
+            let path = segments.by_ref().collect::<Vec<_>>().join("/");
+            if path.is_empty() {
+                return Err(format!("/unix is missing its path in {text}").into());
+            }
+            res.push(Protocol::Unix(format!("/{path}").into()));
+            break;
+        }
+        let component = if protocol_has_value(name) {
+            let Some(value) = segments.next().filter(|value| !value.is_empty()) else {
+                return Err(format!("/{name} is missing its value in {text}").into());
+            };
+            match value.split_once('%') {
+                //an ipv6 scope id, as in /ip6/fe80::1%eth0, becomes /ip6zone/eth0/ip6/fe80::1, the form multiaddr uses for it.
+                Some((address, zone)) if name == "ip6" => format!("/ip6zone/{zone}/ip6/{address}"),
+                _ => format!("/{name}/{value}"),
+            }
+        } else {
+            format!("/{name}")
+        };
+        let component = Multiaddr::from_str(&component)
+            .map_err(|e| format!("invalid component {component} in {text}: {e}"))?;
+        for protocol in component.iter() {
+            res.push(protocol);
+        }
+    }
+    strip_ip6_zone(&mut res);
     Ok(res)
 }
 
-//modifies a Multiaddr by removing the /p2p/peer_id part. libp2p's dial function does not directly support dialing an address with a peer_id attached.
-fn strip_peer_id(addr: &mut Multiaddr) {
-    let last = addr.pop();
-    println!("last {:?}", last);
-    match last {
-        Some(Protocol::P2p(peer_id)) => {
-            let mut addr = Multiaddr::empty();
-            addr.push(Protocol::P2p(peer_id));
-            println!("removing peer id {addr} so this address can be dialed by rust-libp2p");
+//whether a protocol is followed by a value in the text form of a multiaddr. unknown names are taken to have none,
+//so multiaddr rejects them as unknown protocols instead of them swallowing the next component.
+fn protocol_has_value(name: &str) -> bool {
+    matches!(
+        name,
+        "ip4"
+            | "ip6"
+            | "ip6zone"
+            | "ipcidr"
+            | "dns"
+            | "dns4"
+            | "dns6"
+            | "dnsaddr"
+            | "tcp"
+            | "udp"
+            | "dccp"
+            | "sctp"
+            | "p2p"
+            | "onion"
+            | "onion3"
+            | "garlic64"
+            | "garlic32"
+            | "memory"
+            | "certhash"
+            | "sni"
+            | "http-path"
+    )
+}
+
+//rust-libp2p's tcp and quic transports reject /ip6zone addresses, so the zone is dropped before dialing,
+//leaving it to the OS to pick the interface of a link-local address.
+fn strip_ip6_zone(addr: &mut Multiaddr) {
+    let Some(zone) = addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip6zone(zone) => Some(zone.into_owned()),
+        _ => None,
+    }) else {
+        return;
+    };
+    let stripped: Multiaddr = addr
+        .iter()
+        .filter(|protocol| !matches!(protocol, Protocol::Ip6zone(_)))
+        .collect();
+    *addr = stripped;
+    println!(
+        "removing zone {zone} so {addr} can be dialed by rust-libp2p, the OS picks the interface"
+    );
+}
+
+//parse_legacy_multiaddr as a clap value parser, so an invalid address is a usage error instead of failing at dial time.
+pub fn parse_multiaddr_arg(text: &str) -> Result<Multiaddr, String> {
+    parse_legacy_multiaddr(text).map_err(|e| format!("not a valid multiaddr: {e}"))
+}
+
+//advertise the given addresses as our external addresses, so identify and kademlia hand them out instead of the bind address.
+pub fn announce_addresses<B: NetworkBehaviour>(swarm: &mut Swarm<B>, addresses: &[Multiaddr]) {
+    for address in addresses {
+        swarm.add_external_address(address.clone());
+        println!("Announcing external address {address}");
+    }
+}
+
+//a deterministic ed25519 keypair from a one-byte seed, so the same seed always yields the same peer id.
+pub fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = secret_key_seed;
+
+    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+}
+
+//the node identity: read from the identity file or derived from the seed when one is given, random otherwise.
+pub fn local_keypair(
+    secret_key_seed: Option<u8>,
+    identity_file: Option<&Path>,
+    rotate: bool,
+) -> std::io::Result<identity::Keypair> {
+    if let Some(path) = identity_file {
+        return if rotate {
+            rotate_keypair(path)
+        } else {
+            keypair_from_file(path)
+        };
+    }
+    Ok(match secret_key_seed {
+        Some(seed) => generate_ed25519(seed),
+        None => identity::Keypair::generate_ed25519(),
+    })
+}
+
+//a missing identity file is created with a new key, so the node keeps that identity from then on.
+fn keypair_from_file(path: &Path) -> std::io::Result<identity::Keypair> {
+    match fs::read(path) {
+        Ok(bytes) => identity::Keypair::from_protobuf_encoding(&bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid identity file {}: {e}", path.display()),
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = identity::Keypair::generate_ed25519();
+            let bytes = keypair
+                .to_protobuf_encoding()
+                .map_err(std::io::Error::other)?;
+            write_secret(path, &bytes)?;
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+//--rotate-identity: a new key replaces the one in the identity file, which is kept next to it as FILE.old.
+//the node then starts under the new peer id, and peers have to rediscover it.
+fn rotate_keypair(path: &Path) -> std::io::Result<identity::Keypair> {
+    let previous = keypair_from_file(path)?;
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".old");
+    fs::copy(path, &backup)?;
+    let keypair = identity::Keypair::generate_ed25519();
+    let bytes = keypair
+        .to_protobuf_encoding()
+        .map_err(std::io::Error::other)?;
+    write_secret(path, &bytes)?;
+    println!(
+        "Rotated the identity in {}: {} is now {}, the previous key is kept in {}",
+        path.display(),
+        fmt_peer_id(&previous.public().to_peer_id()),
+        fmt_peer_id(&keypair.public().to_peer_id()),
+        Path::new(&backup).display()
+    );
+    Ok(keypair)
+}
+
+//--print-peer-id: the bare peer id on stdout for scripts to capture, the addresses the node listens on to stderr.
+pub fn print_peer_id(keypair: &identity::Keypair, listen_addresses: &[&str]) {
+    let peer_id = keypair.public().to_peer_id();
+    println!("{peer_id}", peer_id = fmt_peer_id(&peer_id));
+    for address in listen_addresses {
+        eprintln!(
+            "listens on {address}/p2p/{peer_id}, port 0 being assigned at startup",
+            peer_id = fmt_peer_id(&peer_id)
+        );
+    }
+}
+
+//the peer id of the /p2p/peer_id part of an address, if it has one.
+pub fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
+
+//an interval whose first tick is one period from now, or none when no period is configured.
+pub fn interval_secs(secs: Option<u64>) -> Option<Interval> {
+    secs.map(|secs| {
+        let period = Duration::from_secs(secs);
+        time::interval_at(Instant::now() + period, period)
+    })
+}
+
+//ticks the interval when there is one and never completes otherwise, so it can sit in a select! branch.
+pub async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+//dialable forms of a listen address to hand to other nodes: an unspecified ip (0.0.0.0 or ::) is expanded to the ip of each
+//network interface, loopback ips are skipped unless asked for, and our peer id is appended so the address can be copy-pasted.
+pub fn dialable_addresses(
+    address: &Multiaddr,
+    local_peer_id: PeerId,
+    include_loopback: bool,
+) -> Vec<Multiaddr> {
+    let expanded = match address.iter().next() {
+        Some(Protocol::Ip4(ip)) if ip.is_unspecified() => {
+            interface_addresses(address, |ip| ip.is_ipv4().then_some(Protocol::from(ip)))
+        }
+        Some(Protocol::Ip6(ip)) if ip.is_unspecified() => {
+            interface_addresses(address, |ip| ip.is_ipv6().then_some(Protocol::from(ip)))
+        }
+        _ => vec![address.clone()],
+    };
+
+    expanded
+        .into_iter()
+        .filter(|address| {
+            include_loopback
+                || !address.iter().any(|protocol| match protocol {
+                    Protocol::Ip4(ip) => ip.is_loopback(),
+                    Protocol::Ip6(ip) => ip.is_loopback(),
+                    _ => false,
+                })
+        })
+        .map(|address| address.with(Protocol::P2p(local_peer_id)))
+        .collect()
+}
+
+//--ready-file: the peer id and dialable addresses as JSON, written once the node listens and removed when dropped,
+//i.e. on a clean shutdown. rewritten with each new listen address, always atomically so readers never see half a file.
+pub struct ReadyFile {
+    path: Option<PathBuf>,
+    addresses: Vec<Multiaddr>,
+}
+
+impl ReadyFile {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        ReadyFile {
+            path,
+            addresses: Vec::new(),
+        }
+    }
+
+    pub fn listening(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        self.addresses.extend(addresses);
+        let addresses = self
+            .addresses
+            .iter()
+            .map(|address| json_string(&address.to_string()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(
+            "{{\"peer_id\":\"{peer_id}\",\"addresses\":[{addresses}]}}\n",
+            peer_id = fmt_peer_id(&peer_id)
+        );
+        match write_atomically(path, &json) {
+            Ok(()) => println!("Wrote ready file {}", path.display()),
+            Err(e) => eprintln!("Failed to write ready file {}: {e}", path.display()),
+        }
+    }
+}
+
+impl Drop for ReadyFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            remove_ready_file(path);
+        }
+    }
+}
+
+fn remove_ready_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Failed to remove ready file {}: {e}", path.display());
+        }
+    }
+}
+
+//a rename within a directory replaces the file in one step.
+pub fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+//write_atomically for secrets such as the identity key, readable only by its owner on unix.
+fn write_secret(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    //a leftover of an interrupted write may be readable by others, create_new makes sure the mode below applies.
+    match fs::remove_file(&temporary) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+//Ctrl-C, or on unix also SIGTERM as sent by container runtimes, either of which shuts a node down cleanly.
+//created once, so a SIGTERM arriving while the event loop is busy is not missed.
+pub struct ShutdownSignal {
+    #[cfg(unix)]
+    terminate: Option<signal::unix::Signal>,
+}
+
+impl ShutdownSignal {
+    pub fn listen() -> Self {
+        ShutdownSignal {
+            #[cfg(unix)]
+            terminate: match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+                Ok(terminate) => Some(terminate),
+                Err(e) => {
+                    eprintln!("Failed to handle SIGTERM, only Ctrl-C shuts down cleanly: {e}");
+                    None
+                }
+            },
+        }
+    }
+
+    //the name of the signal received.
+    pub async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            if let Some(terminate) = &mut self.terminate {
+                return tokio::select! {
+                    _ = signal::ctrl_c() => "Ctrl-C",
+                    _ = terminate.recv() => "SIGTERM",
+                };
+            }
+        }
+        let _ = signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+//the first step of a shutdown: closing the listeners, so no new connections are accepted while the node cleans up.
+//a plain thread ends the process once the grace period is over, even if the cleanup or the runtime's own shutdown stalls,
+//e.g. on the blocking stdin read tokio can't cancel. it removes the ready file itself, as the ReadyFile is never dropped then.
+pub fn begin_shutdown<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    signal: &str,
+    listeners: &[ListenerId],
+    grace: Duration,
+    ready_file: &ReadyFile,
+) {
+    info!(target: "play_p2p::shutdown", "Received {signal}, shutting down within {}s", grace.as_secs());
+    let ready_file = ready_file.path.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(grace);
+        warn!(target: "play_p2p::shutdown", "Cleanup did not finish within {}s, forcing exit", grace.as_secs());
+        if let Some(path) = &ready_file {
+            remove_ready_file(path);
+        }
+        exit::exit(exit::Kind::Interrupted);
+    });
+    for listener_id in listeners {
+        swarm.remove_listener(*listener_id);
+    }
+    info!(target: "play_p2p::shutdown", "Closed {} listener(s), no longer accepting connections", listeners.len());
+}
+
+//the last step of a shutdown, once everything that needs dropping is dropped. exits right away rather than returning
+//from main, whose runtime would wait for the pending stdin read.
+pub fn end_shutdown() -> ! {
+    info!(target: "play_p2p::shutdown", "Shutdown complete");
+    std::process::exit(0)
+}
+
+//stdin read line by line. unlike tokio's lines(), invalid UTF-8 doesn't end the input, and a line longer than the
+//maximum is cut off instead of growing the buffer without bound, e.g. a huge paste exceeding the gossipsub transmit size.
+pub struct StdinLines<R = Stdin> {
+    reader: BufReader<R>,
+    max_length: usize,
+    //the line read so far and the bytes cut off from it, kept here so next_line is cancel safe in a select! branch.
+    line: Vec<u8>,
+    dropped: usize,
+}
+
+impl StdinLines {
+    pub fn new(max_length: usize) -> Self {
+        StdinLines::with_reader(tokio::io::stdin(), max_length)
+    }
+}
+
+impl<R: AsyncRead + Unpin> StdinLines<R> {
+    //any other input, e.g. an in-memory buffer in tests.
+    fn with_reader(reader: R, max_length: usize) -> Self {
+        StdinLines {
+            reader: BufReader::new(reader),
+            max_length,
+            line: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    //the next line without its line ending, none at the end of the input. invalid UTF-8 is replaced with U+FFFD.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.line.is_empty() && self.dropped == 0 {
+                    return Ok(None);
+                }
+                break;
+            }
+            let (chunk, consumed, end_of_line) = match available.iter().position(|&b| b == b'\n') {
+                Some(newline) => (&available[..newline], newline + 1, true),
+                None => (available, available.len(), false),
+            };
+            let room = (self.max_length - self.line.len()).min(chunk.len());
+            self.line.extend_from_slice(&chunk[..room]);
+            self.dropped += chunk.len() - room;
+            self.reader.consume(consumed);
+            if end_of_line {
+                break;
+            }
+        }
+        let mut line = std::mem::take(&mut self.line);
+        let dropped = std::mem::take(&mut self.dropped);
+        if line.last() == Some(&b'\r') && dropped == 0 {
+            line.pop();
+        }
+
+        if dropped > 0 {
+            eprintln!(
+                "Line longer than {} bytes, dropped the last {dropped} bytes",
+                self.max_length
+            );
+        }
+        match String::from_utf8(line) {
+            Ok(line) => Ok(Some(line)),
+            //the cut may have split the last character, which is not worth a warning.
+            Err(e) if dropped > 0 && e.utf8_error().error_len().is_none() => {
+                let valid = e.utf8_error().valid_up_to();
+                let mut line = e.into_bytes();
+                line.truncate(valid);
+                Ok(Some(String::from_utf8(line).expect("valid up to here")))
+            }
+            Err(e) => {
+                eprintln!("Line is not valid UTF-8, invalid bytes replaced with U+FFFD");
+                Ok(Some(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+            }
+        }
+    }
+}
+
+//the text as a JSON string literal, quotes included.
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => escaped.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+//the address with its first (ip) component replaced by each matching interface ip.
+fn interface_addresses(
+    address: &Multiaddr,
+    to_protocol: impl Fn(IpAddr) -> Option<Protocol<'static>>,
+) -> Vec<Multiaddr> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            eprintln!("Failed to list network interfaces: {e}");
+            return vec![address.clone()];
+        }
+    };
+    interfaces
+        .iter()
+        .filter_map(|interface| to_protocol(interface.ip()))
+        .filter_map(|ip| address.replace(0, |_| Some(ip.clone())))
+        .collect()
+}
+
+//the transports a node was built with, to check addresses against before dialing them.
+#[derive(Clone, Copy, Debug)]
+pub struct Transports {
+    pub tcp: bool,
+    pub quic: bool,
+    pub dns: bool,
+}
+
+impl Transports {
+    //the address forms that can be dialed, for error messages.
+    fn dialable(&self) -> String {
+        let mut forms = Vec::new();
+        if self.tcp {
+            forms.push("/tcp/<port>");
+        }
+        if self.quic {
+            forms.push("/udp/<port>/quic-v1");
+        }
+        forms.join(" or ")
+    }
+}
+
+//an error describing why the address can't be dialed with these transports, instead of the confusing failure the dial would end in.
+pub fn check_transports(address: &Multiaddr, transports: Transports) -> Result<(), String> {
+    let not_enabled = |transport: &str| {
+        format!(
+            "{transport} not enabled in this node, dial a {} address instead",
+            transports.dialable()
+        )
+    };
+    let mut protocols = address.iter().peekable();
+    while let Some(protocol) = protocols.next() {
+        match protocol {
+            Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)
+                if !transports.dns =>
+            {
+                return Err(
+                    "DNS resolution not enabled in this node, use an /ip4 or /ip6 address"
+                        .to_string(),
+                );
+            }
+            Protocol::Tcp(_) => match protocols.peek() {
+                Some(Protocol::Ws(_) | Protocol::Wss(_)) => {
+                    return Err(not_enabled("WebSocket transport"))
+                }
+                _ if !transports.tcp => return Err(not_enabled("TCP transport")),
+                _ => {}
+            },
+            Protocol::Udp(_) => match protocols.peek() {
+                Some(Protocol::QuicV1) if !transports.quic => {
+                    return Err(not_enabled("QUIC transport"))
+                }
+                Some(Protocol::QuicV1) => {}
+                Some(Protocol::Quic) => {
+                    return Err("draft QUIC (/quic) is not supported, use /quic-v1".to_string())
+                }
+                _ => return Err(not_enabled("this UDP-based transport")),
+            },
+            Protocol::P2pCircuit => {
+                return Err(
+                    "relayed addresses need a relay client, which is not enabled in this node"
+                        .to_string(),
+                )
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+//dial an address unless it is one of our own, and unless the peer it names is already connected or being dialed.
+//false when the dial was skipped. an address the node has no transport for is an error.
+pub fn dial<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    address: Multiaddr,
+    transports: Transports,
+) -> Result<bool, Box<dyn Error>> {
+    dial_tracked(swarm, address, transports).map(|connection_id| connection_id.is_some())
+}
+
+//dial, returning the id of the connection being dialed, or none when the dial was skipped.
+pub fn dial_tracked<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    address: Multiaddr,
+    transports: Transports,
+) -> Result<Option<ConnectionId>, Box<dyn Error>> {
+    check_transports(&address, transports)?;
+    let peer_id = peer_id_of(&address);
+    let transport_address: Multiaddr = address
+        .iter()
+        .filter(|protocol| !matches!(protocol, Protocol::P2p(_)))
+        .collect();
+    if peer_id == Some(*swarm.local_peer_id())
+        || swarm
+            .listeners()
+            .any(|listener| *listener == transport_address)
+        || swarm
+            .external_addresses()
+            .any(|external| *external == transport_address)
+    {
+        println!("Not dialing our own address {address}");
+        return Ok(None);
+    }
+
+    let opts = match peer_id {
+        Some(peer_id) => DialOpts::peer_id(peer_id)
+            .addresses(vec![address])
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .build(),
+        None => DialOpts::from(address),
+    };
+    let connection_id = opts.connection_id();
+    match swarm.dial(opts) {
+        Ok(()) => Ok(Some(connection_id)),
+        Err(DialError::DialPeerConditionFalse(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//addresses dialed at most `concurrency` at a time, so a long peer list doesn't saturate the network at startup.
+//each address comes with where it was given, e.g. --peer or peers.txt:3, to report the outcome of its dial.
+pub struct DialQueue {
+    queued: VecDeque<(String, Multiaddr)>,
+    in_flight: HashMap<ConnectionId, (String, Multiaddr)>,
+    concurrency: usize,
+    transports: Transports,
+    total: usize,
+    done: usize, //established, failed or skipped
+}
+
+impl DialQueue {
+    pub fn new(
+        addresses: Vec<(String, Multiaddr)>,
+        concurrency: usize,
+        transports: Transports,
+    ) -> Self {
+        DialQueue {
+            total: addresses.len(),
+            queued: addresses.into(),
+            in_flight: HashMap::new(),
+            concurrency,
+            transports,
+            done: 0,
+        }
+    }
+
+    //start queued dials while fewer than `concurrency` are in flight.
+    pub fn fill<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>) {
+        while self.in_flight.len() < self.concurrency {
+            let Some((origin, address)) = self.queued.pop_front() else {
+                return;
+            };
+            match dial_tracked(swarm, address.clone(), self.transports) {
+                Ok(Some(connection_id)) => {
+                    println!(
+                        "Dialing {address} from {origin} ({} of {})",
+                        self.total - self.queued.len(),
+                        self.total
+                    );
+                    self.in_flight.insert(connection_id, (origin, address));
+                }
+                Ok(None) => self.done += 1,
+                Err(e) => {
+                    println!("{origin}: failed to dial {address}: {e}");
+                    self.done += 1;
+                }
+            }
+        }
+    }
+
+    //a connection was established with the given peer or failed. true when it was one of our dials,
+    //which frees a slot for fill.
+    pub fn finished(
+        &mut self,
+        connection_id: ConnectionId,
+        outcome: Result<PeerId, &DialError>,
+    ) -> bool {
+        let Some((origin, address)) = self.in_flight.remove(&connection_id) else {
+            return false;
+        };
+        self.done += 1;
+        match outcome {
+            Ok(peer_id) => println!(
+                "{origin}: connected to {} at {address}",
+                fmt_peer_id(&peer_id)
+            ),
+            Err(error) => println!("{origin}: failed to dial {address}: {error}"),
+        }
+        println!(
+            "Dial queue: {} of {} done, {} in flight, {} queued",
+            self.done,
+            self.total,
+            self.in_flight.len(),
+            self.queued.len()
+        );
+        true
+    }
+}
+
+//the behaviours of a gossipsub binary that the moderation commands act on.
+pub type Moderated<'a> = (
+    &'a mut gossipsub::Behaviour,
+    &'a mut allow_block_list::Behaviour<BlockedPeers>,
+);
+
+//moderation commands: disconnect, ban and unban <peer_id>. false when the command is none of them.
+pub fn handle_peer_command<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    command: &str,
+    moderated: fn(&mut B) -> Moderated<'_>,
+) -> bool {
+    let Some((command, peer_id)) = command.split_once(' ') else {
+        return false;
+    };
+    if !matches!(command, "disconnect" | "ban" | "unban") {
+        return false;
+    }
+    let peer_id = match parse_peer_id(peer_id) {
+        Ok(peer_id) => peer_id,
+        Err(e) => {
+            eprintln!("Invalid peer id {peer_id}: {e}");
+            return true;
         }
+    };
 
-        Some(other) => {
-            println!("other {:?}", other);
-            addr.push(other)
+    let (gossipsub, blocked) = moderated(swarm.behaviour_mut());
+    match command {
+        "unban" => {
+            blocked.unblock_peer(peer_id);
+            println!("Unbanned {peer_id}", peer_id = fmt_peer_id(&peer_id));
+            return true;
+        }
+        //a banned peer can't connect again until it is unbanned.
+        "ban" => {
+            blocked.block_peer(peer_id);
+            println!("Banned {peer_id}", peer_id = fmt_peer_id(&peer_id));
         }
         _ => {}
     }
+    gossipsub.remove_explicit_peer(&peer_id);
+    match swarm.disconnect_peer_id(peer_id) {
+        Ok(()) => println!(
+            "Disconnected from {peer_id}",
+            peer_id = fmt_peer_id(&peer_id)
+        ),
+        Err(()) => println!(
+            "Not connected to {peer_id}",
+            peer_id = fmt_peer_id(&peer_id)
+        ),
+    }
+    true
+}
+
+//the --peer addresses followed by those of --peers-file, labelled with where they were given for the dial queue.
+pub fn startup_peers(
+    peers: &[Multiaddr],
+    peers_file: Option<&Path>,
+) -> Result<Vec<(String, Multiaddr)>, String> {
+    let mut startup_peers: Vec<_> = peers
+        .iter()
+        .map(|address| ("--peer".to_string(), address.clone()))
+        .collect();
+    if let Some(path) = peers_file {
+        startup_peers.extend(read_peers_file(path)?);
+    }
+    Ok(startup_peers)
+}
+
+//one multiaddr per line, ignoring blank lines and # comments. a malformed line is reported and skipped,
+//so the other peers are still dialed.
+fn read_peers_file(path: &Path) -> Result<Vec<(String, Multiaddr)>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("can't read --peers-file {}: {e}", path.display()))?;
+    let mut peers = Vec::new();
+    for (origin, address) in parse_peers_file(path, &text) {
+        match address {
+            Ok(address) => peers.push((origin, address)),
+            Err(e) => eprintln!("{origin}: skipping {e}"),
+        }
+    }
+    Ok(peers)
+}
+
+//each address line of a --peers-file labelled with its file and line number, the malformed ones as errors.
+fn parse_peers_file(path: &Path, text: &str) -> Vec<(String, Result<Multiaddr, String>)> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                return None;
+            }
+            let origin = format!("{}:{}", path.display(), index + 1);
+            Some((
+                origin,
+                parse_multiaddr_arg(line).map_err(|e| format!("{line}, {e}")),
+            ))
+        })
+        .collect()
+}
+
+//peers whose removal is postponed by a grace period, and called off if they come back in time.
+pub struct PendingRemovals {
+    grace: Duration,
+    deadlines: HashMap<PeerId, Instant>,
+}
+
+impl PendingRemovals {
+    pub fn new(grace: Duration) -> Self {
+        PendingRemovals {
+            grace,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    //scheduling a peer again keeps its original deadline.
+    pub fn schedule(&mut self, peer_id: PeerId) {
+        self.deadlines
+            .entry(peer_id)
+            .or_insert_with(|| Instant::now() + self.grace);
+    }
+
+    //true when a removal was pending.
+    pub fn cancel(&mut self, peer_id: &PeerId) -> bool {
+        self.deadlines.remove(peer_id).is_some()
+    }
+
+    //the next peer whose grace period ran out, never completing while none is pending so it can sit in a select! branch.
+    pub async fn expired(&mut self) -> PeerId {
+        let Some((&peer_id, &deadline)) =
+            self.deadlines.iter().min_by_key(|(_, deadline)| **deadline)
+        else {
+            return future::pending().await;
+        };
+        time::sleep_until(deadline).await;
+        self.deadlines.remove(&peer_id);
+        peer_id
+    }
+}
+
+//a multi-interface host reports a listen address per interface and transport at startup. those reported within
+//the settle window of each other are printed as one summary, later ones as they come.
+pub struct ListenLog {
+    settle: Duration,
+    deadline: Option<Instant>, //set while addresses are being collected
+    settled: bool,
+    collected: Vec<String>,
+}
+
+impl ListenLog {
+    //a zero settle window prints every address as it is reported.
+    pub fn new(settle: Duration) -> Self {
+        ListenLog {
+            settle,
+            deadline: None,
+            settled: settle.is_zero(),
+            collected: Vec::new(),
+        }
+    }
+
+    pub fn listening(&mut self, address: String) {
+        if self.settled {
+            info!(target: "play_p2p::swarm", "Listening on {address}");
+            return;
+        }
+        self.collected.push(address);
+        self.deadline = Some(Instant::now() + self.settle);
+    }
+
+    //completes once no address was reported for the settle window, never otherwise, so it can sit in a select! branch.
+    pub async fn check(&self) {
+        match self.deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => future::pending().await,
+        }
+    }
+
+    pub fn summarize(&mut self) {
+        self.deadline = None;
+        self.settled = true;
+        info!(target: "play_p2p::swarm",
+            "Listening on: [{}]",
+            std::mem::take(&mut self.collected).join(", ")
+        );
+    }
+}
+
+//how often the mesh is checked while waiting for it: gossipsub grafts peers in its heartbeat without an event for it.
+const MESH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+//lines published before the topic has a mesh peer are held back until it has one or the timeout elapses.
+//without the wait, a message published right after subscribing reaches no one while the mesh is still forming.
+pub struct MeshWait {
+    started: Instant,
+    deadline: Option<Instant>, //none once the wait is over
+    held: Vec<String>,
+}
+
+impl MeshWait {
+    //no timeout means no wait.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        let started = Instant::now();
+        MeshWait {
+            started,
+            deadline: timeout.map(|timeout| started + timeout),
+            held: Vec::new(),
+        }
+    }
+
+    pub fn waiting(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub fn hold(&mut self, line: &str) {
+        self.held.push(line.to_string());
+        println!(
+            "Waiting for a mesh peer, {} message(s) held back",
+            self.held.len()
+        );
+    }
+
+    //completes at each mesh check while waiting, never otherwise, so it can sit in a select! branch.
+    pub async fn check(&self) {
+        match self.deadline {
+            Some(deadline) => {
+                time::sleep_until(deadline.min(Instant::now() + MESH_CHECK_INTERVAL)).await
+            }
+            None => future::pending().await,
+        }
+    }
+
+    //ends the wait once the topic has a mesh peer or the timeout elapsed, returning the held lines to publish.
+    pub fn ready(
+        &mut self,
+        gossipsub: &gossipsub::Behaviour,
+        topic_hash: &gossipsub::TopicHash,
+    ) -> Vec<String> {
+        let Some(deadline) = self.deadline else {
+            return Vec::new();
+        };
+        let mesh_peers = gossipsub.mesh_peers(topic_hash).count();
+        if mesh_peers > 0 {
+            info!(target: "play_p2p::gossipsub",
+                "Mesh for {topic_hash} ready with {mesh_peers} peer(s) after {:.1}s",
+                self.started.elapsed().as_secs_f64()
+            );
+        } else if Instant::now() >= deadline {
+            warn!(target: "play_p2p::gossipsub",
+                "No mesh peer for {topic_hash} within {:.1}s, publishing anyway",
+                (deadline - self.started).as_secs_f64()
+            );
+        } else {
+            return Vec::new();
+        }
+        self.deadline = None;
+        std::mem::take(&mut self.held)
+    }
+}
+
+//how often idle connections are looked for, at most.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+//closes connections that carried no protocol activity for longer than a timeout, sparing the ones still in use.
+//the swarm's own idle timeout can't tell a connection only kept up by ping and identify from a mesh link or a relayed circuit.
+pub struct IdleReaper {
+    timeout: Duration,
+    connections: HashMap<ConnectionId, (PeerId, Instant)>, //last activity of each connection
+    pinned: HashMap<PeerId, usize>, //relay reservations and circuits of each peer
+    check: Option<Interval>,
+}
+
+impl IdleReaper {
+    //no timeout means connections are never reaped.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        IdleReaper {
+            timeout: timeout.unwrap_or(Duration::MAX),
+            connections: HashMap::new(),
+            pinned: HashMap::new(),
+            check: timeout.map(|timeout| {
+                let period = timeout.min(IDLE_CHECK_INTERVAL);
+                time::interval_at(Instant::now() + period, period)
+            }),
+        }
+    }
+
+    pub fn established(&mut self, connection_id: ConnectionId, peer_id: PeerId) {
+        self.connections
+            .insert(connection_id, (peer_id, Instant::now()));
+    }
+
+    //remaining is the number of connections still open to the peer.
+    pub fn closed(&mut self, connection_id: ConnectionId, peer_id: &PeerId, remaining: u32) {
+        self.connections.remove(&connection_id);
+        //reservations and circuits don't outlive the connections they run on.
+        if remaining == 0 {
+            self.pinned.remove(peer_id);
+        }
+    }
+
+    //the peer used a protocol worth keeping its connections for.
+    pub fn active(&mut self, peer_id: &PeerId) {
+        let now = Instant::now();
+        for (peer, last_active) in self.connections.values_mut() {
+            if peer == peer_id {
+                *last_active = now;
+            }
+        }
+    }
+
+    //keeps the connections of the peer open until unpinned as often, e.g. while it relays through us.
+    pub fn pin(&mut self, peer_id: PeerId) {
+        *self.pinned.entry(peer_id).or_default() += 1;
+    }
+
+    pub fn unpin(&mut self, peer_id: &PeerId) {
+        if let Some(count) = self.pinned.get_mut(peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(peer_id);
+            }
+        }
+        self.active(peer_id);
+    }
+
+    //completes when it's time to look for idle connections, never without a timeout, so it can sit in a select! branch.
+    pub async fn check(&mut self) {
+        tick(&mut self.check).await
+    }
+
+    //the connections idle for longer than the timeout. those of pinned peers and of peers in_use counts as active now.
+    pub fn idle(&mut self, in_use: impl Fn(&PeerId) -> bool) -> Vec<(ConnectionId, PeerId)> {
+        let now = Instant::now();
+        let mut idle = Vec::new();
+        for (connection_id, (peer_id, last_active)) in &mut self.connections {
+            if self.pinned.contains_key(peer_id) || in_use(peer_id) {
+                *last_active = now;
+            } else if now.duration_since(*last_active) >= self.timeout {
+                idle.push((*connection_id, *peer_id));
+            }
+        }
+        idle
+    }
+}
+
+//peers subscribed to each topic, as announced to us through gossipsub.
+#[derive(Default)]
+pub struct Subscribers {
+    topics: HashMap<gossipsub::TopicHash, HashSet<PeerId>>,
+}
+
+impl Subscribers {
+    //the number of subscribers to the topic afterwards.
+    pub fn subscribed(&mut self, peer_id: PeerId, topic: &gossipsub::TopicHash) -> usize {
+        let peers = self.topics.entry(topic.clone()).or_default();
+        peers.insert(peer_id);
+        peers.len()
+    }
+
+    pub fn unsubscribed(&mut self, peer_id: &PeerId, topic: &gossipsub::TopicHash) -> usize {
+        let Some(peers) = self.topics.get_mut(topic) else {
+            return 0;
+        };
+        peers.remove(peer_id);
+        peers.len()
+    }
+
+    //gossipsub forgets the subscriptions of a disconnected peer without an event for each topic.
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        for peers in self.topics.values_mut() {
+            peers.remove(peer_id);
+        }
+    }
+
+    pub fn of(&self, topic: &gossipsub::TopicHash) -> Vec<PeerId> {
+        self.topics
+            .get(topic)
+            .map(|peers| peers.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+//the agent and protocols each connected peer advertised over identify, replaced whenever identify runs again.
+#[derive(Default)]
+pub struct PeerProtocols {
+    peers: HashMap<PeerId, (String, Vec<String>)>,
+}
+
+impl PeerProtocols {
+    pub fn identified(&mut self, peer_id: PeerId, info: &identify::Info) {
+        let mut protocols: Vec<_> = info.protocols.iter().map(ToString::to_string).collect();
+        protocols.sort();
+        self.peers
+            .insert(peer_id, (info.agent_version.clone(), protocols));
+    }
+
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    pub fn print(&self, peer_id: &PeerId) {
+        let Some((agent_version, protocols)) = self.peers.get(peer_id) else {
+            println!(
+                "No protocols known for {}, it is not connected or not identified yet",
+                fmt_peer_id(peer_id)
+            );
+            return;
+        };
+        println!(
+            "{} ({agent_version}) supports {} protocol(s):",
+            fmt_peer_id(peer_id),
+            protocols.len()
+        );
+        for protocol in protocols {
+            println!("  {protocol}");
+        }
+    }
+}
+
+//ping round-trip times per connected peer, over the last RTT_WINDOW pings of each.
+const RTT_WINDOW: usize = 32;
+
+#[derive(Default)]
+pub struct Latencies {
+    peers: HashMap<PeerId, (VecDeque<Duration>, u64)>, //recent rtts, total number of pings
+}
+
+impl Latencies {
+    pub fn record(&mut self, peer_id: PeerId, rtt: Duration) {
+        let (rtts, count) = self.peers.entry(peer_id).or_default();
+        if rtts.len() == RTT_WINDOW {
+            rtts.pop_front();
+        }
+        rtts.push_back(rtt);
+        *count += 1;
+    }
+
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    //one line per peer, fastest on average first.
+    pub fn print(&self) {
+        if self.peers.is_empty() {
+            println!("No round-trip times yet");
+            return;
+        }
+        let mut rows: Vec<_> = self
+            .peers
+            .iter()
+            .filter_map(|(peer_id, (rtts, count))| {
+                let last = *rtts.back()?;
+                let average = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+                let min = *rtts.iter().min()?;
+                let max = *rtts.iter().max()?;
+                Some((average, min, max, last, *count, peer_id))
+            })
+            .collect();
+        rows.sort();
+        println!(
+            "{:>8} {:>8} {:>8} {:>8} {:>7}  peer (ms, over the last {RTT_WINDOW} pings)",
+            "avg", "min", "max", "last", "pings"
+        );
+        for (average, min, max, last, count, peer_id) in rows {
+            println!(
+                "{:>8.1} {:>8.1} {:>8.1} {:>8.1} {count:>7}  {peer_id}",
+                millis(average),
+                millis(min),
+                millis(max),
+                millis(last),
+                peer_id = fmt_peer_id(peer_id)
+            );
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+//a stdin line, split by the command prefix.
+pub enum Input<'a> {
+    //the command with the prefix removed, e.g. "dial /ip4/...".
+    Command(&'a str),
+    Message(&'a str),
+}
+
+//a line starting with the prefix is a command, unless the prefix is doubled, which sends the rest as a message.
+//e.g. with "/", "//shrug" sends "/shrug".
+pub fn parse_input<'a>(line: &'a str, prefix: &str) -> Input<'a> {
+    match line.strip_prefix(prefix) {
+        Some(rest) if rest.starts_with(prefix) => Input::Message(rest),
+        Some(rest) => Input::Command(rest.trim()),
+        None => Input::Message(line),
+    }
+}
+
+pub fn print_help(prefix: &str, commands: &[(&str, &str)]) {
+    println!("Commands:");
+    for (command, description) in commands {
+        println!("  {prefix}{command}: {description}");
+    }
+    println!("Start a message with {prefix}{prefix} to send it with a single leading {prefix}.");
+}
+
+pub fn unknown_command(prefix: &str, command: &str) {
+    println!("Unknown command {prefix}{command}, type {prefix}help for the list of commands");
+}
+
+//what to publish for a stdin line: the line itself, or in binary mode the raw bytes of the file it names.
+pub fn outgoing_payload(line: &str, binary: bool) -> std::io::Result<Vec<u8>> {
+    if binary {
+        fs::read(line.trim())
+    } else {
+        Ok(line.as_bytes().to_vec())
+    }
+}
+
+//the peers of a topic by their role: mesh peers exchange full messages with us, while peers only subscribed to the topic
+//are outside our mesh and just exchange gossip about message ids with us.
+pub struct TopicPeers {
+    pub mesh: Vec<PeerId>,
+    pub subscribed_only: Vec<PeerId>,
+}
+
+pub fn topic_peers(gossipsub: &gossipsub::Behaviour, topic: &gossipsub::TopicHash) -> TopicPeers {
+    let mesh: Vec<PeerId> = gossipsub.mesh_peers(topic).copied().collect();
+    let subscribed_only = gossipsub
+        .all_peers()
+        .filter(|(peer_id, topics)| topics.contains(&topic) && !mesh.contains(*peer_id))
+        .map(|(peer_id, _)| *peer_id)
+        .collect();
+    TopicPeers {
+        mesh,
+        subscribed_only,
+    }
+}
+
+pub fn print_topic_peers(gossipsub: &gossipsub::Behaviour, topic: &gossipsub::TopicHash) {
+    let peers = topic_peers(gossipsub, topic);
+    println!("{} mesh peer(s) for {topic}", peers.mesh.len());
+    for peer_id in &peers.mesh {
+        println!("  {peer_id}", peer_id = fmt_peer_id(peer_id));
+    }
+    println!(
+        "{} peer(s) subscribed outside the mesh",
+        peers.subscribed_only.len()
+    );
+    for peer_id in &peers.subscribed_only {
+        println!("  {peer_id}", peer_id = fmt_peer_id(peer_id));
+    }
+    if peers.mesh.is_empty() && !peers.subscribed_only.is_empty() {
+        println!("Not in the mesh yet: messages only arrive through gossip until the next heartbeat grafts peers");
+    }
+}
+
+//re-add the given explicit peers, for when they got out of sync, and show which peers know of our subscriptions.
+//gossipsub only sends our subscriptions when a connection is established and has no way to send them again,
+//unsubscribing and subscribing would leave the mesh with a PRUNE and keep us out of it for the prune backoff.
+pub fn resync_gossipsub(gossipsub: &mut gossipsub::Behaviour, explicit_peers: &[PeerId]) {
+    for topic in gossipsub.topics() {
+        let peers = topic_peers(gossipsub, topic);
+        println!(
+            "Subscribed to {topic} with {} mesh peer(s) and {} other subscribed peer(s)",
+            peers.mesh.len(),
+            peers.subscribed_only.len()
+        );
+    }
+    for peer_id in explicit_peers {
+        gossipsub.add_explicit_peer(peer_id);
+        println!(
+            "Re-added explicit peer {peer_id}",
+            peer_id = fmt_peer_id(peer_id)
+        );
+    }
+}
+
+//gossipsub only queues a published message for its peers and never confirms delivery. the number of mesh peers, and of
+//subscribed peers flood publishing also sends to, says whether it plausibly reached anyone.
+pub fn print_published(
+    gossipsub: &gossipsub::Behaviour,
+    topic: &gossipsub::TopicHash,
+    id: &gossipsub::MessageId,
+) {
+    let mesh_peers = gossipsub.mesh_peers(topic).count();
+    let subscribed = gossipsub
+        .all_peers()
+        .filter(|(_, topics)| topics.contains(&topic))
+        .count();
+    match (mesh_peers, subscribed) {
+        (0, 0) => println!("Warning: published {id}, but no peer is subscribed to {topic}, it likely reached nobody"),
+        (0, subscribed) => println!(
+            "Warning: published {id} with no mesh peers for {topic}, only sent to the {subscribed} subscribed peer(s)"
+        ),
+        (mesh_peers, _) => println!("Published {id} to {mesh_peers} mesh peer(s)"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use libp2p::swarm::SwarmEvent;
+
+    fn peer_id() -> PeerId {
+        identity::Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    #[test]
+    fn legacy_multiaddr_rejects_malformed_input() {
+        for text in [
+            "",
+            "   ",
+            "ip4/1.2.3.4/tcp/4001",
+            "/ip4//tcp/4001",
+            "/ip4/1.2.3.4/tcp",
+            "/ip4/1.2.3.4//tcp/4001",
+            "/ip4/1.2.3.4/nope/4001",
+            "/ip4/1.2.3.4/tcp/port",
+            "/unix",
+        ] {
+            assert!(
+                parse_legacy_multiaddr(text).is_err(),
+                "{text:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_multiaddr_keeps_ipfs_as_a_value() {
+        let addr = parse_legacy_multiaddr("/dns/ipfs/tcp/4001").unwrap();
+        assert_eq!(addr.to_string(), "/dns/ipfs/tcp/4001");
+    }
+
+    #[test]
+    fn legacy_multiaddr_renames_ipfs_to_p2p() {
+        let peer_id = peer_id();
+        let addr =
+            parse_legacy_multiaddr(&format!("/ip4/1.2.3.4/tcp/4001/ipfs/{peer_id}/p2p-circuit"))
+                .unwrap();
+        assert_eq!(
+            addr.to_string(),
+            format!("/ip4/1.2.3.4/tcp/4001/p2p/{peer_id}/p2p-circuit")
+        );
+    }
+
+    #[test]
+    fn legacy_multiaddr_keeps_a_trailing_peer_id() {
+        let peer_id = peer_id();
+        for name in ["ipfs", "p2p"] {
+            let addr = parse_legacy_multiaddr(&format!("/ip4/1.2.3.4/tcp/4001/{name}/{peer_id}/"))
+                .unwrap();
+            assert_eq!(
+                addr.to_string(),
+                format!("/ip4/1.2.3.4/tcp/4001/p2p/{peer_id}")
+            );
+            assert_eq!(peer_id_of(&addr), Some(peer_id));
+        }
+    }
+
+    #[test]
+    fn legacy_multiaddr_strips_the_ip6_zone() {
+        for text in [
+            "/ip6/fe80::1%eth0/udp/4001/quic-v1",
+            "/ip6zone/eth0/ip6/fe80::1/udp/4001/quic-v1",
+        ] {
+            let addr = parse_legacy_multiaddr(text).unwrap();
+            assert_eq!(addr.to_string(), "/ip6/fe80::1/udp/4001/quic-v1");
+        }
+    }
+
+    #[test]
+    fn legacy_multiaddr_reads_a_unix_path_to_the_end() {
+        let addr = parse_legacy_multiaddr("/unix/run/ipfs/api.sock").unwrap();
+        assert_eq!(
+            addr.iter().collect::<Vec<_>>(),
+            [Protocol::Unix("/run/ipfs/api.sock".into())]
+        );
+    }
+
+    #[test]
+    fn peer_id_round_trips_in_both_formats() {
+        let peer_id = peer_id();
+        for format in [PeerIdFormat::Base58, PeerIdFormat::Base32] {
+            let text = fmt_peer_id_as(&peer_id, format);
+            assert_eq!(parse_peer_id(&text), Ok(peer_id), "{text}");
+        }
+        //a CIDv1 with the libp2p-key codec, as kubo prints it with --ipns-base base32.
+        assert!(fmt_peer_id_as(&peer_id, PeerIdFormat::Base32).starts_with("bafzaa"));
+    }
+
+    #[test]
+    fn peer_id_rejects_invalid_base32() {
+        assert!(parse_peer_id("b1nvalid").is_err());
+        //valid base32, but a raw CIDv1 instead of a libp2p-key one.
+        let mut cid = vec![CID_V1, 0x55];
+        cid.extend(peer_id().to_bytes());
+        assert!(parse_peer_id(&format!("b{}", base32_encode(&cid))).is_err());
+    }
+
+    async fn read_lines(input: &[u8], max_length: usize) -> Vec<String> {
+        let mut lines = StdinLines::with_reader(input, max_length);
+        let mut read = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            read.push(line);
+        }
+        read
+    }
+
+    #[tokio::test]
+    async fn stdin_lines_replace_invalid_utf8() {
+        assert_eq!(
+            read_lines(b"ab\xffcd\nok\n", 64).await,
+            ["ab\u{fffd}cd", "ok"]
+        );
+    }
+
+    #[tokio::test]
+    async fn stdin_lines_cut_over_long_lines() {
+        assert_eq!(
+            read_lines(b"0123456789abc\nnext\n", 8).await,
+            ["01234567", "next"]
+        );
+        //a cut through a character drops what is left of it instead of replacing it.
+        assert_eq!(read_lines("abc\u{e9}\n".as_bytes(), 4).await, ["abc"]);
+    }
+
+    #[tokio::test]
+    async fn stdin_lines_strip_line_endings() {
+        assert_eq!(
+            read_lines(b"crlf\r\n\nlast", 64).await,
+            ["crlf", "", "last"]
+        );
+        assert!(read_lines(b"", 64).await.is_empty());
+    }
+
+    const PEERS_FILE: &str = "# bootstrap peers
+
+/ip4/10.0.0.1/tcp/4001
+  /ip4/10.0.0.2/udp/4001/quic-v1 # second
+not-a-multiaddr
+#/ip4/10.0.0.3/tcp/4001
+";
+
+    #[test]
+    fn peers_file_skips_comments_and_blank_lines() {
+        let parsed = parse_peers_file(Path::new("peers.txt"), PEERS_FILE);
+        let origins: Vec<_> = parsed.iter().map(|(origin, _)| origin.as_str()).collect();
+        assert_eq!(origins, ["peers.txt:3", "peers.txt:4", "peers.txt:5"]);
+        assert_eq!(
+            parsed[1].1.as_ref().unwrap().to_string(),
+            "/ip4/10.0.0.2/udp/4001/quic-v1"
+        );
+        //the malformed line is reported with its own line number.
+        let error = parsed[2].1.as_ref().unwrap_err();
+        assert!(error.starts_with("not-a-multiaddr, "), "{error}");
+    }
+
+    #[test]
+    fn startup_peers_come_after_the_peer_options() {
+        let path = std::env::temp_dir().join(format!("play-net-peers-{}.txt", std::process::id()));
+        fs::write(&path, PEERS_FILE).unwrap();
+        let peer: Multiaddr = "/ip4/10.0.0.9/tcp/4001".parse().unwrap();
+        let peers = startup_peers(std::slice::from_ref(&peer), Some(&path));
+        fs::remove_file(&path).unwrap();
+
+        let peers = peers.unwrap();
+        let origins: Vec<_> = peers.iter().map(|(origin, _)| origin.clone()).collect();
+        assert_eq!(
+            origins,
+            [
+                "--peer".to_string(),
+                format!("{}:3", path.display()),
+                format!("{}:4", path.display())
+            ]
+        );
+        assert_eq!(peers[0].1, peer);
+        assert!(startup_peers(&[], Some(&path)).is_err());
+    }
+
+    //a swarm with only the quic transport and no behaviour, enough to dial.
+    fn quic_swarm() -> Swarm<libp2p::swarm::dummy::Behaviour> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_quic()
+            .with_behaviour(|_| libp2p::swarm::dummy::Behaviour)
+            .unwrap()
+            .build()
+    }
+
+    #[tokio::test]
+    async fn dials_to_ourselves_and_peers_being_dialed_are_skipped() {
+        let mut swarm = quic_swarm();
+        swarm
+            .listen_on("/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap())
+            .unwrap();
+        let listen_address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                break address;
+            }
+        };
+        let transports = Transports {
+            tcp: false,
+            quic: true,
+            dns: false,
+        };
+        let local_peer_id = *swarm.local_peer_id();
+
+        assert_eq!(
+            dial_tracked(&mut swarm, listen_address.clone(), transports).unwrap(),
+            None
+        );
+        let own_peer = listen_address.with(Protocol::P2p(local_peer_id));
+        assert_eq!(
+            dial_tracked(&mut swarm, own_peer, transports).unwrap(),
+            None
+        );
+
+        //a second dial to a peer still being dialed is collapsed into the first.
+        let other: Multiaddr = format!("/ip4/127.0.0.1/udp/9/quic-v1/p2p/{}", peer_id())
+            .parse()
+            .unwrap();
+        assert!(dial_tracked(&mut swarm, other.clone(), transports)
+            .unwrap()
+            .is_some());
+        assert_eq!(dial_tracked(&mut swarm, other, transports).unwrap(), None);
+    }
+
+    fn check(address: &str, tcp: bool, quic: bool) -> Result<(), String> {
+        let transports = Transports {
+            tcp,
+            quic,
+            dns: false,
+        };
+        check_transports(&address.parse().unwrap(), transports)
+    }
+
+    #[test]
+    fn check_transports_matches_the_enabled_transports() {
+        let tcp = "/ip4/10.0.0.1/tcp/4001";
+        let quic = "/ip4/10.0.0.1/udp/4001/quic-v1";
+        assert!(check(tcp, true, false).is_ok());
+        assert!(check(quic, false, true).is_ok());
+        assert!(check(tcp, true, true).is_ok());
+        assert!(check(quic, true, true).is_ok());
+
+        let error = check(quic, true, false).unwrap_err();
+        assert!(error.starts_with("QUIC transport not enabled"), "{error}");
+        assert!(
+            error.ends_with("dial a /tcp/<port> address instead"),
+            "{error}"
+        );
+        let error = check(tcp, false, true).unwrap_err();
+        assert!(error.starts_with("TCP transport not enabled"), "{error}");
+        assert!(
+            error.ends_with("dial a /udp/<port>/quic-v1 address instead"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn check_transports_rejects_what_no_transport_dials() {
+        for (address, reason) in [
+            ("/dns4/example.com/tcp/4001", "DNS resolution not enabled"),
+            (
+                "/ip4/10.0.0.1/tcp/4001/ws",
+                "WebSocket transport not enabled",
+            ),
+            ("/ip4/10.0.0.1/udp/4001/quic", "draft QUIC"),
+            (
+                "/ip4/10.0.0.1/udp/4001",
+                "this UDP-based transport not enabled",
+            ),
+            ("/ip4/10.0.0.1/tcp/4001/p2p-circuit", "relayed addresses"),
+        ] {
+            let error = check(address, true, true).unwrap_err();
+            assert!(error.starts_with(reason), "{address}: {error}");
+        }
+        let peer_id = peer_id();
+        assert!(check(
+            &format!("/ip4/10.0.0.1/tcp/4001/p2p/{peer_id}"),
+            true,
+            false
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn dial_queue_dials_only_what_the_transports_handle() {
+        let mut swarm = quic_swarm();
+        let addresses = [
+            "/ip4/127.0.0.1/tcp/9",
+            "/ip4/127.0.0.1/udp/9/quic-v1",
+            "/ip4/127.0.0.1/tcp/9/ws",
+        ];
+        let addresses = addresses
+            .iter()
+            .map(|address| ("--peer".to_string(), address.parse().unwrap()))
+            .collect();
+        let transports = Transports {
+            tcp: false,
+            quic: true,
+            dns: false,
+        };
+        let mut queue = DialQueue::new(addresses, 3, transports);
+        queue.fill(&mut swarm);
+        let in_flight: Vec<_> = queue
+            .in_flight
+            .values()
+            .map(|(_, address)| address.to_string())
+            .collect();
+        assert_eq!(in_flight, ["/ip4/127.0.0.1/udp/9/quic-v1"]);
+        assert_eq!(queue.done, 2);
+        assert!(queue.queued.is_empty());
+    }
+
+    fn seeded_peer_id(seed: u8) -> PeerId {
+        local_keypair(Some(seed), None, false)
+            .unwrap()
+            .public()
+            .to_peer_id()
+    }
+
+    #[test]
+    fn secret_key_seed_gives_a_stable_peer_id() {
+        assert_eq!(seeded_peer_id(1), seeded_peer_id(1));
+        //the same across builds too, as scripts and docs rely on it.
+        assert_eq!(
+            seeded_peer_id(1).to_string(),
+            "12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X"
+        );
+        assert_ne!(seeded_peer_id(1), seeded_peer_id(2));
+        //without a seed every start is a new identity.
+        let random = || {
+            local_keypair(None, None, false)
+                .unwrap()
+                .public()
+                .to_peer_id()
+        };
+        assert_ne!(random(), random());
+    }
 }